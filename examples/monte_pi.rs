@@ -12,33 +12,34 @@ use std::env;
  */
 
 fn main() {
-    let mut trials = Metric::new(
-        "trials", 1, MetricSem::Counter, 0, 0, MetricType::I64(0),
-        "Trials",
-        "Number of Monte Carlo trials");
-    let mut pi = Metric::new(
-        "pi", 1, MetricSem::Instant, 0, 0, MetricType::F64(0.0),
-        "Estimated Pi",
-        "Estimated value of Pi through Monte Carlo trials");
+    let mut trials = Metric::counter("trials")
+        .item(1)
+        .units(Units::count())
+        .init_val(0u64)
+        .help("Trials", "Number of Monte Carlo trials")
+        .build().unwrap();
+    let mut pi = Metric::instant("pi")
+        .item(2)
+        .init_val(0.0)
+        .help("Estimated Pi", "Estimated value of Pi through Monte Carlo trials")
+        .build().unwrap();
 
     let mut args = env::args();
     let path = args.nth(1).unwrap();
-    let mmv = MMV::new(&path, MMVFlags::empty(), 0);
-    mmv.map(&mut [&mut trials, &mut pi]);
+    let mut mmv = MMV::builder(&path).build().unwrap();
+    mmv.map(&mut [&mut trials, &mut pi]).unwrap();
 
     let mut in_circle = 0;
     let between = Range::new(-1.0, 1.0);
     let mut rng = rand::thread_rng();
 
-    for i in 1..1000001 {
-        trials.set_val(MetricType::I64(i));
+    for _ in 0..1000000 {
+        trials.inc().unwrap();
 
         let x = between.ind_sample(&mut rng);
         let y = between.ind_sample(&mut rng);
         if x*x + y*y <= 1.0 { in_circle += 1; }
-        pi.set_val(MetricType::F64(
-            (in_circle as f64)/(i as f64) * 4.0
-        ));
+        pi.set_val((in_circle as f64)/(trials.val() as f64) * 4.0).unwrap();
 
         thread::sleep(Duration::from_millis(100));
     }
@@ -0,0 +1,61 @@
+extern crate mmv;
+
+use mmv::gc;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+/*
+ * usage: mmv-gc [-n | --dry-run] [<mmv-directory>]
+ *
+ * Removes the MMV files whose writer has exited from the given directory,
+ * or from $PCP_TMP_DIR/mmv. With --dry-run, only lists them. Files that
+ * can't be checked or removed are reported, and make the exit status 1.
+ */
+
+fn usage() -> ! {
+    eprintln!("usage: mmv-gc [-n | --dry-run] [<mmv-directory>]");
+    process::exit(2);
+}
+
+fn main() {
+    let mut dry_run = false;
+    let mut dir = None;
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "-n" | "--dry-run" => dry_run = true,
+            _ if dir.is_none() && !arg.starts_with('-') => dir = Some(PathBuf::from(arg)),
+            _ => usage()
+        }
+    }
+    let dir = dir.unwrap_or_else(gc::default_dir);
+
+    let scan = match gc::find_stale(&dir) {
+        Ok(scan) => scan,
+        Err(err) => {
+            eprintln!("mmv-gc: {}: {}", dir.display(), err);
+            process::exit(1);
+        }
+    };
+    let mut failed = !scan.errors.is_empty();
+    for (path, err) in scan.errors {
+        eprintln!("mmv-gc: {}: {}", path.display(), err);
+    }
+    for file in scan.stale {
+        let kind = if file.partial { "partial file" } else { "file" };
+        if dry_run {
+            println!("would remove {} {} (pid {})", kind, file.path.display(), file.pid);
+            continue;
+        }
+        match file.remove() {
+            Ok(()) => println!("removed {} {} (pid {})", kind, file.path.display(), file.pid),
+            Err(err) => {
+                eprintln!("mmv-gc: {}: {}", file.path.display(), err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
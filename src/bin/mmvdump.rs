@@ -0,0 +1,226 @@
+extern crate mmv;
+
+use mmv::reader::{LabelTarget, Section, Value};
+use mmv::{Error, Reader};
+use std::env;
+use std::process;
+
+/*
+ * usage: mmvdump [--json] <path-to-mmv-file>
+ */
+
+fn section_name(section: Section) -> &'static str {
+    match section {
+        Section::Indoms => "indoms",
+        Section::Instances => "instances",
+        Section::Metrics => "metrics",
+        Section::Values => "values",
+        Section::Strings => "strings",
+        Section::Labels => "labels"
+    }
+}
+
+fn value_string(value: &Value) -> String {
+    match *value {
+        Value::I32(x) => x.to_string(),
+        Value::U32(x) => x.to_string(),
+        Value::I64(x) => x.to_string(),
+        Value::U64(x) => x.to_string(),
+        Value::Float(x) => x.to_string(),
+        Value::Double(x) => x.to_string(),
+        Value::String(ref s) => format!("\"{}\"", s),
+        Value::Elapsed(e) => format!(
+            "{} usec{}", e.total().as_micros(),
+            if e.is_running() { " (running)" } else { "" })
+    }
+}
+
+fn help_string(help: &Option<String>) -> &str {
+    help.as_ref().map_or("<none>", |s| &s[..])
+}
+
+fn dump(path: &str, reader: &Reader) -> Result<(), Error> {
+    println!("MMV file   = {}", path);
+    println!("Version    = {}", reader.version() as u32);
+    println!("Generated  = {}", reader.generation());
+    println!("TOC count  = {}", reader.toc().len());
+    println!("Cluster    = {}", reader.cluster_id());
+    println!("Process    = {}", reader.pid());
+    println!("Flags      = 0x{:x} ({:?})", reader.flags().bits(), reader.flags());
+
+    for (i, entry) in reader.toc().iter().enumerate() {
+        println!();
+        println!("TOC[{}]: {} offset {} ({} entries)",
+            i, section_name(entry.section), entry.offset, entry.n_entries);
+        match entry.section {
+            Section::Indoms => for indom in reader.indoms() {
+                println!("  [{}] {} instances", indom.serial, indom.instances.len());
+                println!("       shorttext={}", help_string(&indom.shorthelp));
+                println!("       longtext={}", help_string(&indom.longhelp));
+            },
+            Section::Instances => for indom in reader.indoms() {
+                for inst in &indom.instances {
+                    println!("  [{}/{}] instance = [{} or \"{}\"]",
+                        indom.serial, inst.id, inst.id, inst.name);
+                }
+            },
+            Section::Metrics => for m in reader.metrics() {
+                println!("  [{}] {}", m.item, m.name);
+                println!("       type={:?} (0x{:x}), sem={:?} (0x{:x})",
+                    m.mtype, m.mtype as u32, m.sem, m.sem as u32);
+                println!("       units=0x{:08x}", m.units.to_raw());
+                match m.indom {
+                    Some(serial) => println!("       indom={}", serial),
+                    None => println!("       (no indom)")
+                }
+                println!("       shorttext={}", help_string(&m.shorthelp));
+                println!("       longtext={}", help_string(&m.longhelp));
+            },
+            Section::Values => for v in reader.values()? {
+                match v.instance {
+                    Some(ref inst) => println!("  {}[\"{}\"] = {}", v.metric, inst, value_string(&v.value)),
+                    None => println!("  {} = {}", v.metric, value_string(&v.value))
+                }
+            },
+            Section::Strings => for &(offset, ref s) in reader.strings() {
+                println!("  [{}] {}", offset, s);
+            },
+            Section::Labels => for l in reader.labels() {
+                let target = match l.target {
+                    LabelTarget::Cluster(cluster) => format!("cluster {}", cluster),
+                    LabelTarget::Indom(serial) => format!("indom {}", serial),
+                    LabelTarget::Item(item) => format!("item {}", item),
+                    LabelTarget::Instance(serial, id) => format!("indom {} instance {}", serial, id)
+                };
+                match l.flags {
+                    0 => println!("  [{}] {}", target, l.payload),
+                    flags => println!("  [{} flags 0x{:x}] {}", target, flags, l.payload)
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn json_str(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_opt_str(s: &Option<String>) -> String {
+    s.as_ref().map_or("null".to_owned(), |s| json_str(s))
+}
+
+fn json_f64(x: f64) -> String {
+    if x.is_finite() { x.to_string() } else { "null".to_owned() }
+}
+
+fn json_value(value: &Value) -> String {
+    match *value {
+        Value::I32(x) => x.to_string(),
+        Value::U32(x) => x.to_string(),
+        Value::I64(x) => x.to_string(),
+        Value::U64(x) => x.to_string(),
+        Value::Float(x) => json_f64(x as f64),
+        Value::Double(x) => json_f64(x),
+        Value::String(ref s) => json_str(s),
+        Value::Elapsed(e) => format!(
+            "{{\"total_usec\":{},\"running\":{}}}",
+            e.total().as_micros(), e.is_running())
+    }
+}
+
+fn dump_json(path: &str, reader: &Reader) -> Result<(), Error> {
+    let toc: Vec<String> = reader.toc().iter()
+        .map(|e| format!("{{\"section\":\"{}\",\"entries\":{},\"offset\":{}}}",
+            section_name(e.section), e.n_entries, e.offset))
+        .collect();
+    let indoms: Vec<String> = reader.indoms().iter()
+        .map(|indom| {
+            let instances: Vec<String> = indom.instances.iter()
+                .map(|inst| format!("{{\"id\":{},\"name\":{}}}", inst.id, json_str(&inst.name)))
+                .collect();
+            format!("{{\"serial\":{},\"instances\":[{}],\"shorttext\":{},\"longtext\":{}}}",
+                indom.serial, instances.join(","),
+                json_opt_str(&indom.shorthelp), json_opt_str(&indom.longhelp))
+        })
+        .collect();
+    let metrics: Vec<String> = reader.metrics().iter()
+        .map(|m| format!(
+            "{{\"name\":{},\"item\":{},\"type\":{},\"sem\":{},\"units\":{},\"indom\":{},\"shorttext\":{},\"longtext\":{}}}",
+            json_str(&m.name), m.item, m.mtype as u32, m.sem as u32, m.units.to_raw(),
+            m.indom.map_or("null".to_owned(), |serial| serial.to_string()),
+            json_opt_str(&m.shorthelp), json_opt_str(&m.longhelp)))
+        .collect();
+    let values: Vec<String> = reader.values()?.iter()
+        .map(|v| format!("{{\"metric\":{},\"instance\":{},\"value\":{}}}",
+            json_str(&v.metric), json_opt_str(&v.instance), json_value(&v.value)))
+        .collect();
+    let strings: Vec<String> = reader.strings().iter()
+        .map(|&(offset, ref s)| format!("{{\"offset\":{},\"string\":{}}}", offset, json_str(s)))
+        .collect();
+    let labels: Vec<String> = reader.labels().iter()
+        .map(|l| {
+            let target = match l.target {
+                LabelTarget::Cluster(cluster) => format!("\"type\":\"cluster\",\"cluster\":{}", cluster),
+                LabelTarget::Indom(serial) => format!("\"type\":\"indom\",\"indom\":{}", serial),
+                LabelTarget::Item(item) => format!("\"type\":\"item\",\"item\":{}", item),
+                LabelTarget::Instance(serial, id) =>
+                    format!("\"type\":\"instance\",\"indom\":{},\"instance\":{}", serial, id)
+            };
+            format!("{{{},\"flags\":{},\"payload\":{}}}", target, l.flags, json_str(&l.payload))
+        })
+        .collect();
+
+    println!(
+        "{{\"file\":{},\"version\":{},\"generation\":{},\"cluster\":{},\"pid\":{},\"flags\":{},\
+         \"toc\":[{}],\"indoms\":[{}],\"metrics\":[{}],\"values\":[{}],\"strings\":[{}],\"labels\":[{}]}}",
+        json_str(path), reader.version() as u32, reader.generation(), reader.cluster_id(), reader.pid(),
+        reader.flags().bits(), toc.join(","), indoms.join(","), metrics.join(","),
+        values.join(","), strings.join(","), labels.join(","));
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("usage: mmvdump [--json] <path-to-mmv-file>");
+    process::exit(2);
+}
+
+fn main() {
+    let mut json = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--json" => json = true,
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => usage()
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    // files are dumped even while being written, with a warning
+    let result = Reader::open_lenient(&path).and_then(|reader| {
+        for warning in reader.warnings() {
+            eprintln!("mmvdump: {}: warning: {}", path, warning);
+        }
+        if json { dump_json(&path, &reader)?; } else { dump(&path, &reader)?; }
+        if reader.warnings().is_empty() && !reader.is_current()? {
+            eprintln!("mmvdump: {}: warning: file re-published while being dumped", path);
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        eprintln!("mmvdump: {}: {}", path, err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,235 @@
+use super::{
+    Elapsed, Error, Indom, LabelValue, MMVFlags, MMVVersion, Metric, MetricSem,
+    MetricValue, MMV, TimeScale, Units
+};
+
+/// Builder for a `Metric`, started by `Metric::counter`, `Metric::instant`,
+/// `Metric::discrete` or `Metric::elapsed`
+///
+/// Invalid names, help text or labels are reported by `build()`.
+pub struct MetricBuilder<T> {
+    name: String,
+    item: Option<u32>,
+    sem: MetricSem,
+    units: Units,
+    indom: Option<Indom>,
+    init_val: T,
+    shorthelp: String,
+    longhelp: String,
+    labels: Vec<(String, LabelValue)>
+}
+
+impl<T: Default> Metric<T> {
+    /// Starts building a metric whose value only ever increases
+    pub fn counter(name: &str) -> MetricBuilder<T> {
+        MetricBuilder::new(name, MetricSem::Counter)
+    }
+
+    /// Starts building a metric whose value can go up and down
+    pub fn instant(name: &str) -> MetricBuilder<T> {
+        MetricBuilder::new(name, MetricSem::Instant)
+    }
+
+    /// Starts building a metric whose value rarely changes
+    pub fn discrete(name: &str) -> MetricBuilder<T> {
+        MetricBuilder::new(name, MetricSem::Discrete)
+    }
+}
+
+impl Metric<Elapsed> {
+    /// Starts building an elapsed time metric, which is always a counter
+    /// of microseconds
+    pub fn elapsed(name: &str) -> MetricBuilder<Elapsed> {
+        MetricBuilder::new(name, MetricSem::Counter)
+            .units(Units::time(TimeScale::Microsec))
+    }
+}
+
+impl<T: Default> MetricBuilder<T> {
+    fn new(name: &str, sem: MetricSem) -> Self {
+        MetricBuilder {
+            name: name.to_owned(),
+            item: None,
+            sem: sem,
+            units: Units::none(),
+            indom: None,
+            init_val: T::default(),
+            shorthelp: String::new(),
+            longhelp: String::new(),
+            labels: Vec::new()
+        }
+    }
+}
+
+impl<T> MetricBuilder<T> {
+    /// Sets the metric's item. Otherwise the metric keeps the item of the
+    /// metric of the same name in the file it's first mapped over, or
+    /// else gets one from a hash of its name, which fails with
+    /// `Error::DuplicateItem` if another metric already has it.
+    pub fn item(mut self, item: u32) -> Self {
+        self.item = Some(item);
+        self
+    }
+
+    pub fn units(mut self, units: Units) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// Gives the metric one value per instance of `indom`
+    pub fn indom(mut self, indom: &Indom) -> Self {
+        self.indom = Some(indom.clone());
+        self
+    }
+
+    /// Sets the value the metric, or each of its instances, starts out as.
+    /// Defaults to zero, or the empty string for string metrics.
+    pub fn init_val(mut self, init_val: T) -> Self {
+        self.init_val = init_val;
+        self
+    }
+
+    pub fn help(mut self, shorthelp: &str, longhelp: &str) -> Self {
+        self.shorthelp = shorthelp.to_owned();
+        self.longhelp = longhelp.to_owned();
+        self
+    }
+
+    pub fn label<V: Into<LabelValue>>(mut self, name: &str, value: V) -> Self {
+        self.labels.push((name.to_owned(), value.into()));
+        self
+    }
+
+    fn finish(self, metric: Result<Metric<T>, Error>) -> Result<Metric<T>, Error>
+        where T: Clone {
+
+        let mut metric = metric?;
+        metric.desc.item = self.item;
+        if let Some(ref indom) = self.indom {
+            metric.set_indom(indom);
+        }
+        for (name, value) in self.labels {
+            metric.add_label(&name, value)?;
+        }
+        Ok(metric)
+    }
+}
+
+impl<T: MetricValue> MetricBuilder<T> {
+    pub fn build(self) -> Result<Metric<T>, Error> {
+        let metric = Metric::new(
+            &self.name, self.item.unwrap_or(0), self.sem, self.units, self.init_val,
+            &self.shorthelp, &self.longhelp);
+        self.finish(metric)
+    }
+}
+
+impl MetricBuilder<String> {
+    pub fn build(self) -> Result<Metric<String>, Error> {
+        let metric = Metric::new_string(
+            &self.name, self.item.unwrap_or(0), self.sem, &self.init_val,
+            &self.shorthelp, &self.longhelp);
+        self.finish(metric)
+    }
+}
+
+impl MetricBuilder<Elapsed> {
+    /// Builds the elapsed time metric, failing with
+    /// `Error::ElapsedSettings` if its units, semantics or initial value
+    /// aren't those set by `Metric::elapsed`
+    pub fn build(self) -> Result<Metric<Elapsed>, Error> {
+        if self.sem != MetricSem::Counter ||
+           self.units != Units::time(TimeScale::Microsec) ||
+           self.init_val != Elapsed::default() {
+            return Err(Error::ElapsedSettings(self.name));
+        }
+        let metric = Metric::new_elapsed(
+            &self.name, self.item.unwrap_or(0), &self.shorthelp, &self.longhelp);
+        self.finish(metric)
+    }
+}
+
+// where the MMV file goes
+enum Location {
+    Path(String),
+    Client(String)
+}
+
+/// Builder for an `MMV`, started by `MMV::builder` or
+/// `MMV::client_builder`
+pub struct MMVBuilder {
+    location: Location,
+    flags: MMVFlags,
+    cluster_id: u32,
+    version: MMVVersion,
+    remove_on_close: bool,
+    labels: Vec<(String, LabelValue)>
+}
+
+impl MMV {
+    /// Starts building an MMV file at `path`
+    pub fn builder(path: &str) -> MMVBuilder {
+        MMVBuilder::new(Location::Path(path.to_owned()))
+    }
+
+    /// Starts building an MMV file named `client` in the `mmv` directory
+    /// under `$PCP_TMP_DIR`, as with `MMV::for_client`
+    pub fn client_builder(client: &str) -> MMVBuilder {
+        MMVBuilder::new(Location::Client(client.to_owned()))
+    }
+}
+
+impl MMVBuilder {
+    fn new(location: Location) -> Self {
+        MMVBuilder {
+            location: location,
+            flags: MMVFlags::empty(),
+            cluster_id: 0,
+            version: MMVVersion::V1,
+            remove_on_close: false,
+            labels: Vec::new()
+        }
+    }
+
+    pub fn flags(mut self, flags: MMVFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn cluster_id(mut self, cluster_id: u32) -> Self {
+        self.cluster_id = cluster_id;
+        self
+    }
+
+    /// Sets the minimum version of the file, as with `MMV::set_version`
+    pub fn version(mut self, version: MMVVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets whether closing the `MMV` removes its file, as with
+    /// `MMV::set_remove_on_close`
+    pub fn remove_on_close(mut self, remove_on_close: bool) -> Self {
+        self.remove_on_close = remove_on_close;
+        self
+    }
+
+    /// Adds a label applying to every metric in the file
+    pub fn label<V: Into<LabelValue>>(mut self, name: &str, value: V) -> Self {
+        self.labels.push((name.to_owned(), value.into()));
+        self
+    }
+
+    pub fn build(self) -> Result<MMV, Error> {
+        let mut mmv = match self.location {
+            Location::Path(ref path) => MMV::new(path, self.flags, self.cluster_id)?,
+            Location::Client(ref client) => MMV::for_client(client, self.flags, self.cluster_id)?
+        };
+        mmv.set_version(self.version);
+        mmv.set_remove_on_close(self.remove_on_close);
+        for (name, value) in self.labels {
+            mmv.add_label(&name, value)?;
+        }
+        Ok(mmv)
+    }
+}
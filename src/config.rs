@@ -0,0 +1,64 @@
+use std::env;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::PathBuf;
+
+const PCP_CONF: &str = "/etc/pcp.conf";
+const DEFAULT_PCP_TMP_DIR: &str = "/var/lib/pcp/tmp";
+
+// world-writable and sticky, as PCP creates it, so that clients run by
+// any user can create their own files but not remove others'
+const MMV_DIR_MODE: u32 = 0o1777;
+
+// value of `var` in a pcp.conf style file of `NAME=value` lines
+fn conf_var(path: &str, var: &str) -> Option<String> {
+    let file = File::open(path).ok()?;
+    for line in BufReader::new(file).lines() {
+        let line = line.ok()?;
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut split = line.splitn(2, '=');
+        if split.next() == Some(var) {
+            let value = split.next().unwrap_or("").trim_matches(|c| c == '"' || c == '\'');
+            if !value.is_empty() {
+                return Some(value.to_owned());
+            }
+        }
+    }
+    None
+}
+
+/// `$PCP_TMP_DIR`, falling back to its setting in `$PCP_CONF` or
+/// `/etc/pcp.conf`, and then to PCP's default
+pub fn pcp_tmp_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("PCP_TMP_DIR") {
+        if !dir.is_empty() {
+            return PathBuf::from(dir);
+        }
+    }
+    let conf = env::var("PCP_CONF").unwrap_or_else(|_| PCP_CONF.to_owned());
+    conf_var(&conf, "PCP_TMP_DIR")
+        .map_or_else(|| PathBuf::from(DEFAULT_PCP_TMP_DIR), PathBuf::from)
+}
+
+/// The `mmv` directory under `pcp_tmp_dir()`, created if it doesn't
+/// exist yet
+pub fn mmv_dir() -> io::Result<PathBuf> {
+    let tmp_dir = pcp_tmp_dir();
+    let dir = tmp_dir.join("mmv");
+    if !dir.is_dir() {
+        fs::create_dir_all(&tmp_dir)?;
+        match DirBuilder::new().mode(MMV_DIR_MODE).create(&dir) {
+            Ok(()) => {
+                // the mode given to mkdir is masked by the umask
+                fs::set_permissions(&dir, fs::Permissions::from_mode(MMV_DIR_MODE))?;
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {},
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(dir)
+}
@@ -0,0 +1,158 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use super::MetricType;
+
+/// Errors from defining, mapping and updating metrics
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error on the MMV file
+    Io(io::Error),
+    /// Client name that is empty or contains a path separator
+    InvalidClientName(String),
+    /// Metric or instance name longer than 255 bytes
+    NameTooLong(String),
+    /// Help text longer than 255 bytes
+    HelpTooLong(String),
+    /// String value longer than the 255 bytes that fit in a string block
+    StringTooLong(usize),
+    /// Instance domain with a serial of 0
+    ZeroIndomSerial,
+    /// Instance id or name used twice in an instance domain
+    DuplicateInstance(String),
+    /// No instance with the given name in the instance domain
+    UnknownInstance(String),
+    /// Different instance domains with the same serial
+    ConflictingIndoms(u32),
+    /// Metric name that isn't made of dot-separated components of
+    /// letters, digits and underscores starting with a letter
+    InvalidMetricName(String),
+    /// Cluster id outside the 12-bit range of PMID clusters
+    ClusterIdOutOfRange(u32),
+    /// Item id outside the 10-bit range of PMID items
+    ItemOutOfRange(u32),
+    /// Item id used by more than one metric
+    DuplicateItem(u32),
+    /// Name used by more than one metric
+    DuplicateName(String),
+    /// No metric with the given name in the registry
+    UnknownMetric(String),
+    /// Metric in an MMV file of a different type than asked for
+    WrongMetricType(String, MetricType),
+    /// Elapsed time metric built with units, semantics or an initial
+    /// value other than those set by `Metric::elapsed`
+    ElapsedSettings(String),
+    /// Label name not starting with a letter or containing characters
+    /// other than letters, digits and underscores
+    InvalidLabelName(String),
+    /// Label value that can't be represented in JSON
+    InvalidLabelValue(String),
+    /// Label with a JSON payload too long for a label block
+    LabelTooLong(String),
+    /// Label name used twice for the same cluster, indom, metric or instance
+    DuplicateLabel(String),
+    /// Unit dimension or count scale outside the range -8 to 7
+    DimensionOutOfRange(i8),
+    /// Value of a metric with an instance domain set without an instance
+    HasIndom,
+    /// Counter metric decremented
+    CounterDecrease,
+    /// Metric updated before being mapped into an MMV file
+    NotMapped,
+    /// MMV file mapped by a parent process, which a forked child can't
+    /// re-publish at the same path
+    InheritedFile(String),
+    /// MMV file still being written by the live process with the given
+    /// pid, which can't be resumed
+    FileInUse(String, i32),
+    /// File that isn't a well-formed MMV file
+    InvalidFile(String),
+    /// Existing MMV file whose metrics differ from those it was to be
+    /// resumed with, and how
+    SchemaMismatch(String),
+    /// MMV file whose header generation numbers differ, i.e. one that is
+    /// still being written
+    GenerationMismatch(i64, i64)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::InvalidClientName(ref name) =>
+                write!(f, "invalid client name \"{}\"", name),
+            Error::NameTooLong(ref name) =>
+                write!(f, "name \"{}\" is longer than 255 bytes", name),
+            Error::HelpTooLong(ref help) =>
+                write!(f, "help text \"{}\" is longer than 255 bytes", help),
+            Error::StringTooLong(len) =>
+                write!(f, "string of {} bytes is longer than 255 bytes", len),
+            Error::ZeroIndomSerial =>
+                write!(f, "instance domain serial must be non-zero"),
+            Error::DuplicateInstance(ref name) =>
+                write!(f, "duplicate instance \"{}\"", name),
+            Error::UnknownInstance(ref name) =>
+                write!(f, "no instance named \"{}\"", name),
+            Error::ConflictingIndoms(serial) =>
+                write!(f, "conflicting instance domains with serial {}", serial),
+            Error::InvalidMetricName(ref name) =>
+                write!(f, "invalid metric name \"{}\"", name),
+            Error::ClusterIdOutOfRange(cluster_id) =>
+                write!(f, "cluster id {} is greater than 4095", cluster_id),
+            Error::ItemOutOfRange(item) =>
+                write!(f, "item {} is greater than 1023", item),
+            Error::DuplicateItem(item) =>
+                write!(f, "item {} is used by more than one metric", item),
+            Error::DuplicateName(ref name) =>
+                write!(f, "name \"{}\" is used by more than one metric", name),
+            Error::UnknownMetric(ref name) =>
+                write!(f, "no metric named \"{}\"", name),
+            Error::WrongMetricType(ref name, mtype) =>
+                write!(f, "metric \"{}\" is of type {:?}", name, mtype),
+            Error::ElapsedSettings(ref name) =>
+                write!(f, "elapsed time metric \"{}\" must be a counter of microseconds starting at zero", name),
+            Error::InvalidLabelName(ref name) =>
+                write!(f, "invalid label name \"{}\"", name),
+            Error::InvalidLabelValue(ref name) =>
+                write!(f, "invalid value for label \"{}\"", name),
+            Error::LabelTooLong(ref name) =>
+                write!(f, "label \"{}\" is too long", name),
+            Error::DuplicateLabel(ref name) =>
+                write!(f, "duplicate label \"{}\"", name),
+            Error::DimensionOutOfRange(dim) =>
+                write!(f, "unit dimension {} is outside -8 to 7", dim),
+            Error::HasIndom =>
+                write!(f, "metric has an instance domain, set its instances instead"),
+            Error::CounterDecrease =>
+                write!(f, "counter metrics can't decrease"),
+            Error::NotMapped =>
+                write!(f, "metric is not mapped into an MMV file"),
+            Error::InheritedFile(ref path) =>
+                write!(f, "MMV file {} was mapped by a parent process", path),
+            Error::FileInUse(ref path, pid) =>
+                write!(f, "MMV file {} is in use by process {}", path, pid),
+            Error::InvalidFile(ref what) =>
+                write!(f, "invalid MMV file: {}", what),
+            Error::SchemaMismatch(ref what) =>
+                write!(f, "MMV file doesn't match its metrics: {}", what),
+            Error::GenerationMismatch(gen1, gen2) =>
+                write!(f, "MMV file generations {} and {} differ", gen1, gen2)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,244 @@
+//! Finding and removing MMV files left behind by exited processes
+//!
+//! Files with the `PROCESS` flag carry the pid of their writer, and are
+//! ignored by the MMV PMDA once that process has exited. A process that
+//! crashes never removes its file, so such files pile up in the `mmv`
+//! directory until something collects them.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use super::{config, is_alive, Error, MMVFlags, HDR_LEN, PROCESS};
+
+/// MMV file whose writer has exited
+#[derive(Clone, PartialEq, Debug)]
+pub struct StaleFile {
+    pub path: PathBuf,
+    /// Process id of the writer
+    pub pid: i32,
+    /// Whether the file is a temporary file that its writer exited while
+    /// writing, rather than a published one
+    pub partial: bool
+}
+
+/// The `mmv` directory under `$PCP_TMP_DIR`, where `MMV::for_client`
+/// puts files
+pub fn default_dir() -> PathBuf {
+    config::pcp_tmp_dir().join("mmv")
+}
+
+// pid and flags in the header of the MMV file at `path`, or `None` if it
+// isn't an MMV file
+fn read_header(path: &Path) -> io::Result<Option<(i32, MMVFlags)>> {
+    let mut header = [0; HDR_LEN as usize];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut header) {
+        Ok(()) => {},
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err)
+    }
+    if &header[..4] != b"MMV\0" {
+        return Ok(None);
+    }
+    let flags = MMVFlags::from_bits_truncate(LittleEndian::read_u32(&header[28..]));
+    Ok(Some((LittleEndian::read_i32(&header[32..]), flags)))
+}
+
+// pid in the name of a temporary file written by `MMV::map`, which is
+// `.{name}.{pid}.tmp`
+fn tmp_file_pid(file_name: &str) -> Option<i32> {
+    if !file_name.starts_with('.') || !file_name.ends_with(".tmp") {
+        return None;
+    }
+    let stem = &file_name[..file_name.len() - ".tmp".len()];
+    stem.rsplit('.').next()?.parse().ok()
+}
+
+// the file at `path` as a stale file, if it is one
+fn check_file(path: &Path) -> io::Result<Option<StaleFile>> {
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Ok(None)
+    };
+    let (pid, partial) = match tmp_file_pid(&file_name) {
+        Some(pid) => (pid, true),
+        None => match read_header(path)? {
+            Some((pid, flags)) if flags.contains(PROCESS) => (pid, false),
+            _ => return Ok(None)
+        }
+    };
+    // non-positive pids name process groups rather than a process
+    if pid <= 0 || is_alive(pid) {
+        return Ok(None);
+    }
+    Ok(Some(StaleFile {
+        path: path.to_owned(),
+        pid: pid,
+        partial: partial
+    }))
+}
+
+/// Result of looking for stale files in a directory
+#[derive(Debug)]
+pub struct Scan {
+    /// Stale files, in order of path
+    pub stale: Vec<StaleFile>,
+    /// Files that couldn't be checked, e.g. for lack of permission to read
+    /// them, along with the error checking each
+    pub errors: Vec<(PathBuf, Error)>
+}
+
+/// Finds the stale files in `dir`, i.e. MMV files with the `PROCESS`
+/// flag whose writer has exited, and temporary files left by writers
+/// that exited while writing.
+///
+/// Files without the `PROCESS` flag are never stale, as their writer
+/// can't be told apart from any other process. A file that can't be
+/// checked is skipped and reported in `Scan::errors`, and only failing
+/// to list the directory fails the whole scan.
+pub fn find_stale<P: AsRef<Path>>(dir: P) -> Result<Scan, Error> {
+    let mut scan = Scan {
+        stale: Vec::new(),
+        errors: Vec::new()
+    };
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let checked = entry.file_type().and_then(|file_type|
+            if file_type.is_file() { check_file(&path) } else { Ok(None) });
+        match checked {
+            Ok(Some(file)) => scan.stale.push(file),
+            Ok(None) => {},
+            // removed since listing the directory
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => scan.errors.push((path, err.into()))
+        }
+    }
+    scan.stale.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(scan)
+}
+
+impl StaleFile {
+    /// Removes the file, unless it has been replaced since it was found
+    /// by a file that isn't stale, e.g. one re-published by a new process
+    /// for the same client. A file that is already gone is not an error.
+    pub fn remove(&self) -> Result<(), Error> {
+        let result = match check_file(&self.path) {
+            Ok(Some(ref file)) if file == self => fs::remove_file(&self.path),
+            Ok(_) => return Ok(()),
+            Err(err) => Err(err)
+        };
+        match result {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.map_err(Error::from)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use nix::sys::wait::waitpid;
+    use nix::unistd::{fork, getpid, ForkResult};
+    use std::env;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use super::*;
+
+    // pid of a process that has exited
+    fn dead_pid() -> i32 {
+        match fork().unwrap() {
+            ForkResult::Child => process::exit(0),
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+                child
+            }
+        }
+    }
+
+    fn write_header(path: &Path, pid: i32, flags: MMVFlags) {
+        let mut header = [0; HDR_LEN as usize];
+        header[..4].copy_from_slice(b"MMV\0");
+        LittleEndian::write_u32(&mut header[28..], flags.bits());
+        LittleEndian::write_i32(&mut header[32..], pid);
+        fs::write(path, &header[..]).unwrap();
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mmv-gc-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn find_stale_files() -> Result<(), Error> {
+        let dir = test_dir("find");
+        let dead = dead_pid();
+        write_header(&dir.join("dead"), dead, PROCESS);
+        write_header(&dir.join("live"), getpid(), PROCESS);
+        write_header(&dir.join("no-process"), dead, MMVFlags::empty());
+        File::create(dir.join(format!(".dead.{}.tmp", dead)))?;
+        File::create(dir.join(format!(".live.{}.tmp", getpid())))?;
+        fs::write(dir.join("short"), b"MMV")?;
+        fs::create_dir(dir.join("subdir"))?;
+
+        let scan = find_stale(&dir)?;
+        assert!(scan.errors.is_empty());
+        assert_eq!(scan.stale, [
+            StaleFile { path: dir.join(format!(".dead.{}.tmp", dead)), pid: dead, partial: true },
+            StaleFile { path: dir.join("dead"), pid: dead, partial: false }
+        ]);
+        for file in &scan.stale {
+            file.remove()?;
+            assert!(fs::metadata(&file.path).is_err());
+        }
+        // already gone
+        scan.stale[0].remove()?;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn remove_keeps_republished_file() -> Result<(), Error> {
+        let dir = test_dir("republished");
+        let path = dir.join("client");
+        write_header(&path, dead_pid(), PROCESS);
+        let scan = find_stale(&dir)?;
+        assert_eq!(scan.stale.len(), 1);
+        // a new process writes the file for the same client meanwhile
+        write_header(&path, getpid(), PROCESS);
+        scan.stale[0].remove()?;
+        assert!(fs::metadata(&path).is_ok());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_file_skipped() -> Result<(), Error> {
+        let dir = test_dir("unreadable");
+        let dead = dead_pid();
+        write_header(&dir.join("a"), dead, PROCESS);
+        write_header(&dir.join("b"), dead, PROCESS);
+        write_header(&dir.join("c"), dead, PROCESS);
+        fs::set_permissions(dir.join("b"), fs::Permissions::from_mode(0o000))?;
+
+        let scan = find_stale(&dir)?;
+        let stale: Vec<_> = scan.stale.iter().map(|file| file.path.clone()).collect();
+        // the file can still be read by root
+        if File::open(dir.join("b")).is_ok() {
+            assert_eq!(stale, [dir.join("a"), dir.join("b"), dir.join("c")]);
+        } else {
+            assert_eq!(stale, [dir.join("a"), dir.join("c")]);
+            assert_eq!(scan.errors.len(), 1);
+            assert_eq!(scan.errors[0].0, dir.join("b"));
+        }
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
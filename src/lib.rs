@@ -6,273 +6,2203 @@ extern crate nix;
 extern crate bitflags;
 
 use memmap::{Mmap, Protection, MmapViewSync};
-use byteorder::{LittleEndian, WriteBytesExt};
-use std::fs::OpenOptions;
-use std::io::{Cursor, Write};
-use std::mem::transmute;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{fence, AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use nix::errno::Errno;
+use nix::sys::signal::kill;
 use nix::unistd::getpid;
+use reader::Section;
+
+mod builder;
+mod config;
+mod error;
+pub mod gc;
+pub mod reader;
+mod registry;
+mod shared;
+mod units;
+
+pub use builder::{MetricBuilder, MMVBuilder};
+pub use error::Error;
+pub use reader::Reader;
+pub use registry::Registry;
+pub use shared::SharedMMV;
+pub use units::{SpaceScale, TimeScale, Units};
 
 const HDR_LEN: u64 = 40;
+const GEN1_OFFSET: u64 = 8;
+const GEN2_OFFSET: u64 = 16;
 const TOC_BLOCK_LEN: u64 = 16;
-const METRIC_BLOCK_LEN: u64 = 104;
+const INDOM_BLOCK_LEN: u64 = 32;
+const INSTANCE_V1_BLOCK_LEN: u64 = 80;
+const INSTANCE_V2_BLOCK_LEN: u64 = 24;
+const METRIC_V1_BLOCK_LEN: u64 = 104;
+const METRIC_V2_BLOCK_LEN: u64 = 48;
 const VALUE_BLOCK_LEN: u64 = 32;
 const STRING_BLOCK_LEN: u64 = 256;
+const LABEL_BLOCK_LEN: u64 = 256;
+const LABEL_PAYLOAD_MAX_LEN: u64 = 244;
+const LABEL_NAME_MAX_LEN: u64 = 256;
 const METRIC_NAME_MAX_LEN: u64 = 64;
+const INSTANCE_NAME_MAX_LEN: u64 = 64;
+// items are the low 10 bits of a PMID, and clusters the 12 bits above
+const ITEM_MAX: u32 = (1 << 10) - 1;
+const CLUSTER_MAX: u32 = (1 << 12) - 1;
+
+const INDOM_TOC_TYPE: u32 = 1;
+const INSTANCE_TOC_TYPE: u32 = 2;
+const METRIC_TOC_TYPE: u32 = 3;
+const VALUE_TOC_TYPE: u32 = 4;
+const STRING_TOC_TYPE: u32 = 5;
+const LABEL_TOC_TYPE: u32 = 6;
+
+const LABEL_INDOM: u32 = 1 << 2;
+const LABEL_CLUSTER: u32 = 1 << 3;
+const LABEL_ITEM: u32 = 1 << 4;
+const LABEL_INSTANCES: u32 = 1 << 5;
+const IN_NULL: i32 = -1;
+
+bitflags! {
+    pub struct MMVFlags: u32 {
+        const NOPREFIX = 1;
+        const PROCESS = 2;
+        const SENTINEL = 4;
+    }
+}
+
+/// On-disk format version of an MMV file
+///
+/// Version 1 stores metric and instance names inline in their blocks,
+/// limiting them to 63 bytes. Version 2 stores them in the string section
+/// instead, allowing names of up to 255 bytes. Version 3 adds labels.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MMVVersion {
+    V1 = 1,
+    V2 = 2,
+    V3 = 3
+}
+
+impl MMVVersion {
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(MMVVersion::V1),
+            2 => Some(MMVVersion::V2),
+            3 => Some(MMVVersion::V3),
+            _ => None
+        }
+    }
+}
+
+/// Value of a label, stored as JSON in the MMV file
+#[derive(Clone, PartialEq, Debug)]
+pub enum LabelValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String)
+}
+
+impl From<bool> for LabelValue {
+    fn from(b: bool) -> Self {
+        LabelValue::Bool(b)
+    }
+}
+
+impl From<f64> for LabelValue {
+    fn from(x: f64) -> Self {
+        LabelValue::Number(x)
+    }
+}
+
+impl From<i64> for LabelValue {
+    fn from(x: i64) -> Self {
+        LabelValue::Number(x as f64)
+    }
+}
+
+impl<'a> From<&'a str> for LabelValue {
+    fn from(s: &'a str) -> Self {
+        LabelValue::String(s.to_owned())
+    }
+}
+
+impl From<String> for LabelValue {
+    fn from(s: String) -> Self {
+        LabelValue::String(s)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct Label {
+    name: String,
+    payload: String
+}
+
+impl Label {
+    // Label names must start with a letter and contain only letters,
+    // digits and underscores, and the whole {"name":value} JSON payload
+    // must fit in a label block.
+    fn new(name: &str, value: LabelValue) -> Result<Self, Error> {
+        let valid_name =
+            name.len() < LABEL_NAME_MAX_LEN as usize &&
+            name.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(Error::InvalidLabelName(name.to_owned()));
+        }
+
+        let json_val = match value {
+            LabelValue::Null => "null".to_owned(),
+            LabelValue::Bool(b) => b.to_string(),
+            LabelValue::Number(x) => {
+                if !x.is_finite() {
+                    return Err(Error::InvalidLabelValue(name.to_owned()));
+                }
+                x.to_string()
+            },
+            LabelValue::String(s) => {
+                let mut quoted = String::from("\"");
+                for c in s.chars() {
+                    match c {
+                        '"' => quoted.push_str("\\\""),
+                        '\\' => quoted.push_str("\\\\"),
+                        c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => quoted.push(c)
+                    }
+                }
+                quoted.push('"');
+                quoted
+            }
+        };
+
+        let payload = format!("{{\"{}\":{}}}", name, json_val);
+        if payload.len() >= LABEL_PAYLOAD_MAX_LEN as usize {
+            return Err(Error::LabelTooLong(name.to_owned()));
+        }
+        Ok(Label {
+            name: name.to_owned(),
+            payload: payload
+        })
+    }
+}
+
+fn add_label(labels: &mut Vec<Label>, name: &str, value: LabelValue) -> Result<(), Error> {
+    if labels.iter().any(|l| l.name == name) {
+        return Err(Error::DuplicateLabel(name.to_owned()));
+    }
+    labels.push(Label::new(name, value)?);
+    Ok(())
+}
+
+// A label as laid out in the label section
+struct LabelBlock {
+    flags: u32,
+    identity: u32,
+    internal: i32,
+    payload: String
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MetricSem {
+    Counter = 1,
+    Instant = 3,
+    Discrete = 4
+}
+
+impl MetricSem {
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(MetricSem::Counter),
+            3 => Some(MetricSem::Instant),
+            4 => Some(MetricSem::Discrete),
+            _ => None
+        }
+    }
+}
+
+/// Type of a metric's value, with discriminants matching the MMV type codes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MetricType {
+    I32 = 0,
+    U32 = 1,
+    I64 = 2,
+    U64 = 3,
+    Float = 4,
+    Double = 5,
+    String = 6,
+    Elapsed = 9
+}
+
+impl MetricType {
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(MetricType::I32),
+            1 => Some(MetricType::U32),
+            2 => Some(MetricType::I64),
+            3 => Some(MetricType::U64),
+            4 => Some(MetricType::Float),
+            5 => Some(MetricType::Double),
+            6 => Some(MetricType::String),
+            9 => Some(MetricType::Elapsed),
+            _ => None
+        }
+    }
+}
+
+/// Numeric Rust types that can be stored as the value of a metric
+pub trait MetricValue: Copy + PartialOrd {
+    fn metric_type() -> MetricType;
+    /// Bits of the value as laid out in an MMV value block
+    fn to_raw(self) -> u64;
+    fn from_raw(raw: u64) -> Self;
+    fn zero() -> Self;
+    fn one() -> Self;
+    /// Sum of the values, wrapping around for integers
+    fn plus(self, other: Self) -> Self;
+    /// Difference of the values, wrapping around for integers
+    fn minus(self, other: Self) -> Self;
+}
+
+macro_rules! impl_metric_value {
+    ($t:ty, $mtype:expr, $zero:expr, $one:expr, $to_raw:expr, $from_raw:expr, $plus:expr, $minus:expr) => {
+        impl MetricValue for $t {
+            fn metric_type() -> MetricType {
+                $mtype
+            }
+
+            fn to_raw(self) -> u64 {
+                $to_raw(self)
+            }
+
+            fn from_raw(raw: u64) -> Self {
+                $from_raw(raw)
+            }
+
+            fn zero() -> Self {
+                $zero
+            }
+
+            fn one() -> Self {
+                $one
+            }
+
+            fn plus(self, other: Self) -> Self {
+                $plus(self, other)
+            }
+
+            fn minus(self, other: Self) -> Self {
+                $minus(self, other)
+            }
+        }
+    }
+}
+
+impl_metric_value!(i32, MetricType::I32, 0, 1,
+    |x: i32| x as u32 as u64, |x: u64| x as u32 as i32,
+    |x: i32, y| x.wrapping_add(y), |x: i32, y| x.wrapping_sub(y));
+impl_metric_value!(u32, MetricType::U32, 0, 1,
+    |x: u32| x as u64, |x: u64| x as u32,
+    |x: u32, y| x.wrapping_add(y), |x: u32, y| x.wrapping_sub(y));
+impl_metric_value!(i64, MetricType::I64, 0, 1,
+    |x: i64| x as u64, |x: u64| x as i64,
+    |x: i64, y| x.wrapping_add(y), |x: i64, y| x.wrapping_sub(y));
+impl_metric_value!(u64, MetricType::U64, 0, 1,
+    |x: u64| x, |x: u64| x,
+    |x: u64, y| x.wrapping_add(y), |x: u64, y| x.wrapping_sub(y));
+impl_metric_value!(f32, MetricType::Float, 0.0, 1.0,
+    |x: f32| x.to_bits() as u64, |x: u64| f32::from_bits(x as u32),
+    |x: f32, y| x + y, |x: f32, y| x - y);
+impl_metric_value!(f64, MetricType::Double, 0.0, 1.0,
+    |x: f64| x.to_bits(), |x: u64| f64::from_bits(x),
+    |x: f64, y| x + y, |x: f64, y| x - y);
+
+/// Value of an elapsed time metric
+///
+/// An elapsed time metric accumulates the time spent in intervals marked
+/// by `start()` and `stop()`. While an interval is in progress, its start
+/// time is recorded in the value block so that readers can include the
+/// time spent in it so far.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Elapsed {
+    // total microseconds of completed intervals
+    total: i64,
+    // start of the in-progress interval in microseconds since the epoch,
+    // or 0 if there's none
+    start: i64
+}
+
+impl Elapsed {
+    /// Total time spent in completed intervals
+    pub fn total(&self) -> Duration {
+        Duration::from_micros(self.total as u64)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.start != 0
+    }
+}
+
+fn now_usec() -> i64 {
+    let now = time::get_time();
+    now.sec*1_000_000 + (now.nsec/1000) as i64
+}
+
+// Whether a process with id `pid` exists. Processes of other users
+// can't be signalled but still exist.
+fn is_alive(pid: i32) -> bool {
+    !matches!(kill(pid, None), Err(nix::Error::Sys(Errno::ESRCH)))
+}
+
+// Mapping of a published MMV file, shared with the views of its value
+// blocks. Closing it unmaps the file at once, after which the views fail
+// to write with `Error::NotMapped` rather than touch the unmapped memory.
+struct Mapping {
+    state: RwLock<MappingState>
+}
+
+enum MappingState {
+    Mapped(MmapViewSync),
+    // contents of the file when it was unmapped, to read values back from
+    Closed(Vec<u8>)
+}
+
+impl Mapping {
+    fn new(mmap: MmapViewSync) -> Self {
+        Mapping {
+            state: RwLock::new(MappingState::Mapped(mmap))
+        }
+    }
+
+    // nothing run under the lock panics, so a poisoned lock guards
+    // nothing half-done
+    fn state(&self) -> RwLockReadGuard<'_, MappingState> {
+        self.state.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // runs `f` on the start of the mapping, keeping it mapped meanwhile
+    fn with<R, F: FnOnce(*mut u8) -> R>(&self, f: F) -> Result<R, Error> {
+        match *self.state() {
+            MappingState::Mapped(ref mmap) => Ok(f(mmap.ptr() as *mut u8)),
+            MappingState::Closed(_) => Err(Error::NotMapped)
+        }
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        let offset = offset as usize;
+        match *self.state() {
+            MappingState::Mapped(ref mmap) => unsafe {
+                (*(mmap.ptr().add(offset) as *const AtomicU64)).load(Ordering::Acquire)
+            },
+            MappingState::Closed(ref contents) => unsafe {
+                ptr::read_unaligned(contents[offset..offset + 8].as_ptr() as *const u64)
+            }
+        }
+    }
+
+    // flushes the file and unmaps it, waiting for views using it to finish
+    fn close(&self) -> io::Result<()> {
+        let mut state = self.state.write().unwrap_or_else(|err| err.into_inner());
+        let (flushed, contents) = match *state {
+            MappingState::Mapped(ref mmap) => (mmap.flush(), unsafe { mmap.as_slice() }.to_vec()),
+            MappingState::Closed(_) => return Ok(())
+        };
+        *state = MappingState::Closed(contents);
+        flushed
+    }
+}
+
+// View of a value block in a mapped MMV file, along with the offset of
+// the pair of string blocks holding the value of a string metric
+#[doc(hidden)]
+pub struct ValueView {
+    mapping: Arc<Mapping>,
+    offset: u64,
+    strings_offset: Option<u64>
+}
+
+impl ValueView {
+    // Runs `f` on the value and extra fields of the value block. Value
+    // blocks are 32 bytes long and start at a multiple of 8 in the
+    // page-aligned mapping, so their fields can be accessed atomically.
+    fn with_fields<R, F: FnOnce(&AtomicU64, &AtomicU64) -> R>(&self, f: F) -> Result<R, Error> {
+        let offset = self.offset as usize;
+        self.mapping.with(|start| unsafe {
+            let block = start.add(offset);
+            f(&*(block as *const AtomicU64), &*(block.add(8) as *const AtomicU64))
+        })
+    }
+
+    fn read_raw(&self) -> u64 {
+        self.mapping.read_u64(self.offset)
+    }
+
+    fn write_raw(&self, raw: u64) -> Result<(), Error> {
+        self.with_fields(|value, _| value.store(raw, Ordering::Release))
+    }
+
+    // Atomically adds `delta` to the value, or subtracts it if `increase`
+    // is false, unless that would make a counter go down.
+    fn add<T: MetricValue>(&self, sem: MetricSem, delta: T, increase: bool) -> Result<(), Error> {
+        let decreases = if increase { delta < T::zero() } else { delta > T::zero() };
+        if decreases && sem == MetricSem::Counter {
+            return Err(Error::CounterDecrease);
+        }
+        self.with_fields(|value, _| {
+            let _ = value.fetch_update(Ordering::AcqRel, Ordering::Acquire, |raw| {
+                let val = T::from_raw(raw);
+                Some(if increase { val.plus(delta) } else { val.minus(delta) }.to_raw())
+            });
+        })
+    }
+
+    fn read_elapsed(&self) -> Result<Elapsed, Error> {
+        self.with_fields(|value, extra| Elapsed {
+            total: value.load(Ordering::Acquire) as i64,
+            start: (extra.load(Ordering::Acquire) as i64).wrapping_neg()
+        })
+    }
+
+    // string block the value points to, up to its nul
+    fn read_str(&self) -> Result<String, Error> {
+        let offset = self.offset as usize;
+        self.mapping.with(|start| {
+            let extra = unsafe { &*(start.add(offset + 8) as *const AtomicU64) };
+            let block_offset = extra.load(Ordering::Acquire) as usize;
+            let buf = unsafe { slice::from_raw_parts(start.add(block_offset), STRING_BLOCK_LEN as usize) };
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        })
+    }
+
+    // An in-progress interval is recorded in the extra field as the
+    // negated start time, which readers add the current time to. The
+    // extra field is stored first, so that a reader never sees the total
+    // of a stopped interval with its start still in progress.
+    fn write_elapsed(&self, elapsed: Elapsed) -> Result<(), Error> {
+        self.with_fields(|value, extra| {
+            extra.store(elapsed.start.wrapping_neg() as u64, Ordering::Release);
+            value.store(elapsed.total as u64, Ordering::Release);
+        })
+    }
+
+    // Writes the string into whichever string block the value isn't
+    // currently pointing to, then atomically points the value's extra
+    // field at it. The otherwise unused value field counts the writes,
+    // and is odd while one is in progress, so that a reader whose block
+    // was overwritten under it by a second write can tell and read again.
+    fn write_str(&self, s: &str) -> Result<(), Error> {
+        let strings_offset = self.strings_offset.unwrap();
+        let offset = self.offset as usize;
+        self.mapping.with(|start| {
+            let seq = unsafe { &*(start.add(offset) as *const AtomicU64) };
+            let extra = unsafe { &*(start.add(offset + 8) as *const AtomicU64) };
+            let writing = seq.load(Ordering::Relaxed) | 1;
+            seq.store(writing, Ordering::Relaxed);
+            fence(Ordering::Release);
+
+            let cur_offset = extra.load(Ordering::Relaxed);
+            let next = if cur_offset == strings_offset { 1 } else { 0 };
+            let block_len = STRING_BLOCK_LEN as usize;
+            let next_offset = strings_offset as usize + next*block_len;
+            let buf = unsafe { slice::from_raw_parts_mut(start.add(next_offset), block_len) };
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            buf[..s.len()].copy_from_slice(s.as_bytes());
+
+            extra.store(next_offset as u64, Ordering::Release);
+            seq.store(writing.wrapping_add(1), Ordering::Release);
+        })
+    }
+}
+
+// Value block of a metric or instance, shared with its atomic handles.
+// Mapping the metric again points the slot at the value block in the new
+// file, holding its write lock from reading the values out of the old
+// file until then, so that no update made through a handle is lost.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct ValueSlot {
+    view: Arc<RwLock<Option<ValueView>>>
+}
+
+impl ValueSlot {
+    fn new(view: Option<ValueView>) -> Self {
+        ValueSlot {
+            view: Arc::new(RwLock::new(view))
+        }
+    }
+
+    // nothing run under the lock panics, as with `Mapping`
+    fn read(&self) -> RwLockReadGuard<'_, Option<ValueView>> {
+        self.view.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn lock(&self) -> RwLockWriteGuard<'_, Option<ValueView>> {
+        self.view.write().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn with<R, F: FnOnce(&ValueView) -> Result<R, Error>>(&self, f: F) -> Result<R, Error> {
+        match *self.read() {
+            Some(ref mv) => f(mv),
+            None => Err(Error::NotMapped)
+        }
+    }
+}
+
+fn write_val<T: MetricValue>(mmap_view: &ValueSlot, new_val: T) -> Result<(), Error> {
+    mmap_view.with(|mv| mv.write_raw(new_val.to_raw()))
+}
+
+fn add_val<T: MetricValue>(
+    mmap_view: &ValueSlot, sem: MetricSem, delta: T, increase: bool) -> Result<(), Error> {
+
+    mmap_view.with(|mv| mv.add(sem, delta, increase))
+}
+
+// mapped value, which atomic handles may have changed, or the value the
+// metric was created with if it isn't mapped
+fn read_val<T: MetricValue>(mmap_view: &ValueSlot, val: T) -> T {
+    mmap_view.read().as_ref().map_or(val, |mv| T::from_raw(mv.read_raw()))
+}
+
+fn atomic_handle<T: MetricValue>(mmap_view: &ValueSlot, sem: MetricSem) -> Result<AtomicHandle<T>, Error> {
+    mmap_view.with(|_| Ok(AtomicHandle {
+        slot: mmap_view.clone(),
+        sem: sem,
+        _val: PhantomData
+    }))
+}
+
+/// Handle to the value of a mapped numeric metric, or of one of its
+/// instances, which can be shared between threads
+///
+/// Values are written and read with single atomic 64-bit operations on
+/// the value block, so readers never see a partially written value.
+///
+/// The handle follows the metric when it is mapped again, e.g. by a
+/// `Registry`, with updates made meanwhile waiting for it to be moved to
+/// the new file. Once the metric's file is unmapped without the metric
+/// being mapped again, by closing its `MMV` or unregistering it, updates
+/// fail with `Error::NotMapped` and the value reads as it was when the
+/// file was unmapped.
+pub struct AtomicHandle<T> {
+    slot: ValueSlot,
+    sem: MetricSem,
+    _val: PhantomData<T>
+}
+
+impl<T: MetricValue> AtomicHandle<T> {
+    pub fn val(&self) -> T {
+        read_val(&self.slot, T::zero())
+    }
+
+    pub fn set_val(&self, new_val: T) -> Result<(), Error> {
+        write_val(&self.slot, new_val)
+    }
+
+    pub fn inc(&self) -> Result<(), Error> {
+        add_val(&self.slot, self.sem, T::one(), true)
+    }
+
+    pub fn inc_by(&self, n: T) -> Result<(), Error> {
+        add_val(&self.slot, self.sem, n, true)
+    }
+
+    /// Decrements the value, failing for counters
+    pub fn dec(&self) -> Result<(), Error> {
+        add_val(&self.slot, self.sem, T::one(), false)
+    }
+
+    /// Decreases the value by `n`, failing for counters unless `n` is
+    /// zero or negative
+    pub fn dec_by(&self, n: T) -> Result<(), Error> {
+        add_val(&self.slot, self.sem, n, false)
+    }
+
+    /// Adds `delta` to the value, failing for counters if it's negative
+    pub fn add(&self, delta: T) -> Result<(), Error> {
+        add_val(&self.slot, self.sem, delta, true)
+    }
+}
+
+impl<T> Clone for AtomicHandle<T> {
+    fn clone(&self) -> Self {
+        AtomicHandle {
+            slot: self.slot.clone(),
+            sem: self.sem,
+            _val: PhantomData
+        }
+    }
+}
+
+fn start_elapsed(mmap_view: &ValueSlot, elapsed: &mut Elapsed) -> Result<(), Error> {
+    if elapsed.is_running() {
+        return Ok(());
+    }
+    let mut new_elapsed = *elapsed;
+    new_elapsed.start = now_usec();
+    mmap_view.with(|mv| mv.write_elapsed(new_elapsed))?;
+    *elapsed = new_elapsed;
+    Ok(())
+}
+
+fn stop_elapsed(mmap_view: &ValueSlot, elapsed: &mut Elapsed) -> Result<(), Error> {
+    if !elapsed.is_running() {
+        return Ok(());
+    }
+    let new_elapsed = Elapsed {
+        total: elapsed.total + (now_usec() - elapsed.start),
+        start: 0
+    };
+    mmap_view.with(|mv| mv.write_elapsed(new_elapsed))?;
+    *elapsed = new_elapsed;
+    Ok(())
+}
+
+fn write_str_val(mmap_view: &ValueSlot, new_val: &str) -> Result<(), Error> {
+    if new_val.len() >= STRING_BLOCK_LEN as usize {
+        return Err(Error::StringTooLong(new_val.len()));
+    }
+    mmap_view.with(|mv| mv.write_str(new_val))
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.len() >= STRING_BLOCK_LEN as usize {
+        return Err(Error::NameTooLong(name.to_owned()));
+    }
+    Ok(())
+}
+
+// PMNS names are dot-separated components of letters, digits and
+// underscores, each starting with a letter
+fn check_metric_name(name: &str) -> Result<(), Error> {
+    check_name(name)?;
+    let valid = name.split('.').all(|component|
+        component.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+        component.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+    if !valid {
+        return Err(Error::InvalidMetricName(name.to_owned()));
+    }
+    Ok(())
+}
+
+fn check_item(item: u32) -> Result<(), Error> {
+    if item > ITEM_MAX {
+        return Err(Error::ItemOutOfRange(item));
+    }
+    Ok(())
+}
+
+// 32-bit FNV-1a, which unlike the std hashers is fixed across Rust
+// versions, so that names hash to the same items on every run
+fn name_hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5, |hash, b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+fn check_help(help: &str) -> Result<(), Error> {
+    if help.len() >= STRING_BLOCK_LEN as usize {
+        return Err(Error::HelpTooLong(help.to_owned()));
+    }
+    Ok(())
+}
+
+/// An instance domain, i.e. a set of instances over which a metric can
+/// take a separate value for each instance
+#[derive(Clone)]
+pub struct Indom {
+    serial: u32,
+    instances: Vec<(i32, String)>,
+    shorttext: String,
+    longtext: String,
+    // shared with the clones of the indom kept by the metrics created with
+    // it, so that labels added later apply to those metrics too
+    labels: Arc<RwLock<IndomLabels>>
+}
+
+#[derive(PartialEq)]
+struct IndomLabels {
+    labels: Vec<Label>,
+    instance_labels: Vec<(i32, Vec<Label>)>
+}
+
+impl PartialEq for Indom {
+    fn eq(&self, other: &Indom) -> bool {
+        self.serial == other.serial &&
+        self.instances == other.instances &&
+        self.shorttext == other.shorttext &&
+        self.longtext == other.longtext &&
+        (Arc::ptr_eq(&self.labels, &other.labels) || *self.labels() == *other.labels())
+    }
+}
+
+impl Indom {
+    /// Creates an instance domain from `(internal id, external name)` pairs.
+    ///
+    /// The serial must be non-zero, as an indom of 0 in a metric block
+    /// denotes a metric without an instance domain.
+    pub fn new(
+        serial: u32, instances: &[(i32, &str)],
+        shorthelp: &str, longhelp: &str) -> Result<Self, Error> {
+
+        if serial == 0 {
+            return Err(Error::ZeroIndomSerial);
+        }
+        for (i, &(id, name)) in instances.iter().enumerate() {
+            check_name(name)?;
+            if instances[..i].iter().any(|&(prev_id, prev_name)| prev_id == id || prev_name == name) {
+                return Err(Error::DuplicateInstance(name.to_owned()));
+            }
+        }
+        check_help(shorthelp)?;
+        check_help(longhelp)?;
+
+        Ok(Indom {
+            serial: serial,
+            instances: instances.iter()
+                .map(|&(id, name)| (id, name.to_owned()))
+                .collect(),
+            shorttext: shorthelp.to_owned(),
+            longtext: longhelp.to_owned(),
+            labels: Arc::new(RwLock::new(IndomLabels {
+                labels: Vec::new(),
+                instance_labels: instances.iter().map(|&(id, _)| (id, Vec::new())).collect()
+            }))
+        })
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Adds a label applying to the whole instance domain.
+    ///
+    /// The label also applies to the metrics already created with this
+    /// instance domain, from when they are next mapped.
+    pub fn add_label<V: Into<LabelValue>>(&mut self, name: &str, value: V) -> Result<(), Error> {
+        add_label(&mut self.labels_mut().labels, name, value.into())
+    }
+
+    /// Adds a label applying to the instance with external name
+    /// `instance`, like `add_label`
+    pub fn add_instance_label<V: Into<LabelValue>>(
+        &mut self, instance: &str, name: &str, value: V) -> Result<(), Error> {
+
+        let id = self.instances.iter()
+            .find(|&&(_, ref inst_name)| inst_name == instance)
+            .map(|&(id, _)| id)
+            .ok_or_else(|| Error::UnknownInstance(instance.to_owned()))?;
+        let mut indom_labels = self.labels_mut();
+        let labels = indom_labels.instance_labels.iter_mut()
+            .find(|&&mut (inst_id, _)| inst_id == id)
+            .map(|&mut (_, ref mut labels)| labels)
+            .unwrap();
+        add_label(labels, name, value.into())
+    }
+
+    // nothing run under the lock panics, as with `Mapping`
+    fn labels(&self) -> RwLockReadGuard<'_, IndomLabels> {
+        self.labels.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn labels_mut(&self) -> RwLockWriteGuard<'_, IndomLabels> {
+        self.labels.write().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Value handle of a single instance of a metric with an instance domain
+pub struct Instance<T> {
+    id: i32,
+    name: String,
+    sem: MetricSem,
+    val: T,
+    mmap_view: ValueSlot
+}
+
+impl<T> Instance<T> {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<T: MetricValue> Instance<T> {
+    pub fn val(&self) -> T {
+        read_val(&self.mmap_view, self.val)
+    }
+
+    /// Returns a handle for updating the value from any thread
+    pub fn atomic_handle(&self) -> Result<AtomicHandle<T>, Error> {
+        atomic_handle(&self.mmap_view, self.sem)
+    }
+
+    pub fn inc(&mut self) -> Result<(), Error> {
+        self.inc_by(T::one())
+    }
+
+    pub fn inc_by(&mut self, n: T) -> Result<(), Error> {
+        add_val(&self.mmap_view, self.sem, n, true)
+    }
+
+    /// Decrements the value, failing for counters
+    pub fn dec(&mut self) -> Result<(), Error> {
+        self.dec_by(T::one())
+    }
+
+    /// Decreases the value by `n`, failing for counters unless `n` is
+    /// zero or negative
+    pub fn dec_by(&mut self, n: T) -> Result<(), Error> {
+        add_val(&self.mmap_view, self.sem, n, false)
+    }
+
+    /// Adds `delta` to the value, failing for counters if it's negative
+    pub fn add(&mut self, delta: T) -> Result<(), Error> {
+        self.inc_by(delta)
+    }
+
+    pub fn set_val(&mut self, new_val: T) -> Result<(), Error> {
+        write_val(&self.mmap_view, new_val)?;
+        self.val = new_val;
+        Ok(())
+    }
+}
+
+impl Instance<Elapsed> {
+    pub fn val(&self) -> Elapsed {
+        self.val
+    }
+
+    pub fn start(&mut self) -> Result<(), Error> {
+        start_elapsed(&self.mmap_view, &mut self.val)
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
+        stop_elapsed(&self.mmap_view, &mut self.val)
+    }
+}
+
+impl Instance<String> {
+    pub fn val(&self) -> &str {
+        &self.val
+    }
+
+    pub fn set_val(&mut self, new_val: &str) -> Result<(), Error> {
+        write_str_val(&self.mmap_view, new_val)?;
+        self.val = new_val.to_owned();
+        Ok(())
+    }
+}
+
+// Value-type independent definition of a metric
+#[doc(hidden)]
+pub struct MetricDesc {
+    name: String,
+    // None if the item is assigned when mapping
+    item: Option<u32>,
+    mtype: MetricType,
+    sem: MetricSem,
+    indom: Option<Indom>,
+    units: Units,
+    shorttext: String,
+    longtext: String,
+    labels: Vec<Label>
+}
+
+impl MetricDesc {
+    fn n_values(&self) -> u64 {
+        match self.indom {
+            Some(ref indom) => indom.instances.len() as u64,
+            None => 1
+        }
+    }
+
+    // each string value is double-buffered across two string blocks
+    fn n_value_strings(&self) -> u64 {
+        match self.mtype {
+            MetricType::String => 2*self.n_values(),
+            _ => 0
+        }
+    }
+}
+
+// Initial contents of a value block
+#[doc(hidden)]
+#[derive(Clone)]
+pub enum InitVal<'a> {
+    Raw(u64),
+    Elapsed(Elapsed),
+    Str(&'a str)
+}
+
+/// A metric of any value type that can be mapped into an MMV file
+pub trait MMVMetric {
+    #[doc(hidden)]
+    fn desc(&self) -> &MetricDesc;
+    // initial value of each value block of the metric
+    #[doc(hidden)]
+    fn init_vals(&self) -> Vec<InitVal<'_>>;
+    // keeps the item the metric was given when mapped, if it had none
+    #[doc(hidden)]
+    fn set_item(&mut self, item: u32);
+    // slot of each value block of the metric
+    #[doc(hidden)]
+    fn slots(&self) -> Vec<ValueSlot>;
+    // reads the values of the metric back from each of its value blocks
+    // that is mapped
+    #[doc(hidden)]
+    fn load_vals(&mut self, views: &[Option<&ValueView>]);
+}
+
+pub struct Metric<T> {
+    desc: MetricDesc,
+    val: T,
+    mmap_view: ValueSlot,
+    instances: Vec<Instance<T>>
+}
+
+impl<T: Clone> Metric<T> {
+    fn with_type(
+        name: &str, item: u32, mtype: MetricType, sem: MetricSem,
+        units: Units, init_val: T,
+        shorthelp: &str, longhelp: &str) -> Result<Self, Error> {
+
+        check_metric_name(name)?;
+        check_item(item)?;
+        check_help(shorthelp)?;
+        check_help(longhelp)?;
+
+        Ok(Metric {
+            desc: MetricDesc {
+                name: name.to_owned(),
+                item: Some(item),
+                mtype: mtype,
+                sem: sem,
+                indom: None,
+                units: units,
+                shorttext: shorthelp.to_owned(),
+                longtext: longhelp.to_owned(),
+                labels: Vec::new()
+            },
+            val: init_val,
+            mmap_view: ValueSlot::new(None),
+            instances: Vec::new()
+        })
+    }
+
+    fn set_indom(&mut self, indom: &Indom) {
+        self.instances = indom.instances.iter()
+            .map(|&(id, ref name)| Instance {
+                id: id,
+                name: name.clone(),
+                sem: self.desc.sem,
+                val: self.val.clone(),
+                mmap_view: ValueSlot::new(None)
+            })
+            .collect();
+        self.desc.indom = Some(indom.clone());
+    }
+}
+
+impl<T> Metric<T> {
+    pub fn add_label<V: Into<LabelValue>>(&mut self, name: &str, value: V) -> Result<(), Error> {
+        add_label(&mut self.desc.labels, name, value.into())
+    }
+
+    pub fn instance(&self, name: &str) -> Option<&Instance<T>> {
+        self.instances.iter().find(|inst| inst.name == name)
+    }
+
+    pub fn instance_mut(&mut self, name: &str) -> Option<&mut Instance<T>> {
+        self.instances.iter_mut().find(|inst| inst.name == name)
+    }
+
+    pub fn instances_mut(&mut self) -> std::slice::IterMut<'_, Instance<T>> {
+        self.instances.iter_mut()
+    }
+
+    fn check_no_indom(&self) -> Result<(), Error> {
+        match self.desc.indom {
+            Some(_) => Err(Error::HasIndom),
+            None => Ok(())
+        }
+    }
+
+    fn load_vals_with<F: Fn(&ValueView) -> Option<T>>(&mut self, views: &[Option<&ValueView>], read: F) {
+        let vals = match self.desc.indom {
+            Some(_) => self.instances.iter_mut().map(|inst| &mut inst.val).collect(),
+            None => vec![&mut self.val]
+        };
+        for (val, view) in vals.into_iter().zip(views) {
+            if let Some(new_val) = view.and_then(&read) {
+                *val = new_val;
+            }
+        }
+    }
 
-bitflags! {
-    pub struct MMVFlags: u32 {
-        const NOPREFIX = 1;
-        const PROCESS = 2;
-        const SENTINEL = 4;
+    fn slot_vec(&self) -> Vec<ValueSlot> {
+        match self.desc.indom {
+            Some(_) => self.instances.iter().map(|inst| inst.mmap_view.clone()).collect(),
+            None => vec![self.mmap_view.clone()]
+        }
     }
 }
 
-#[derive(Copy, Clone)]
-pub enum MetricSem {
-    Counter = 1,
-    Instant = 3,
-    Discrete = 4
+impl<T: MetricValue> Metric<T> {
+    pub fn new(
+        name: &str, item: u32, sem: MetricSem,
+        units: Units, init_val: T,
+        shorthelp: &str, longhelp: &str) -> Result<Self, Error> {
+
+        Metric::with_type(
+            name, item, T::metric_type(), sem, units, init_val, shorthelp, longhelp)
+    }
+
+    pub fn val(&self) -> T {
+        read_val(&self.mmap_view, self.val)
+    }
+
+    /// Returns a handle for updating the value from any thread. Metrics
+    /// with an instance domain have a handle per instance instead.
+    pub fn atomic_handle(&self) -> Result<AtomicHandle<T>, Error> {
+        self.check_no_indom()?;
+        atomic_handle(&self.mmap_view, self.desc.sem)
+    }
+
+    /// Increments the value in place in the mapped file
+    pub fn inc(&mut self) -> Result<(), Error> {
+        self.inc_by(T::one())
+    }
+
+    pub fn inc_by(&mut self, n: T) -> Result<(), Error> {
+        self.check_no_indom()?;
+        add_val(&self.mmap_view, self.desc.sem, n, true)
+    }
+
+    /// Decrements the value, failing for counters
+    pub fn dec(&mut self) -> Result<(), Error> {
+        self.dec_by(T::one())
+    }
+
+    /// Decreases the value by `n`, failing for counters unless `n` is
+    /// zero or negative
+    pub fn dec_by(&mut self, n: T) -> Result<(), Error> {
+        self.check_no_indom()?;
+        add_val(&self.mmap_view, self.desc.sem, n, false)
+    }
+
+    /// Adds `delta` to the value, failing for counters if it's negative
+    pub fn add(&mut self, delta: T) -> Result<(), Error> {
+        self.inc_by(delta)
+    }
+
+    pub fn set_val(&mut self, new_val: T) -> Result<(), Error> {
+        self.check_no_indom()?;
+        write_val(&self.mmap_view, new_val)?;
+        self.val = new_val;
+        Ok(())
+    }
 }
 
-#[derive(Copy, Clone)]
-pub enum MetricType {
-    I64(i64),
-    F64(f64)
+impl Metric<String> {
+    /// Creates a string metric. String values can be at most 255 bytes
+    /// long.
+    pub fn new_string(
+        name: &str, item: u32, sem: MetricSem,
+        init_val: &str,
+        shorthelp: &str, longhelp: &str) -> Result<Self, Error> {
+
+        if init_val.len() >= STRING_BLOCK_LEN as usize {
+            return Err(Error::StringTooLong(init_val.len()));
+        }
+        Metric::with_type(
+            name, item, MetricType::String, sem, Units::none(), init_val.to_owned(), shorthelp, longhelp)
+    }
+
+    /// Creates a string metric having one value per instance of `indom`,
+    /// each starting out as `init_val`
+    pub fn new_string_with_indom(
+        name: &str, item: u32, sem: MetricSem,
+        indom: &Indom, init_val: &str,
+        shorthelp: &str, longhelp: &str) -> Result<Self, Error> {
+
+        let mut metric = Metric::new_string(
+            name, item, sem, init_val, shorthelp, longhelp)?;
+        metric.set_indom(indom);
+        Ok(metric)
+    }
+
+    pub fn val(&self) -> &str {
+        &self.val
+    }
+
+    /// Sets the string value, failing if it is longer than 255 bytes.
+    ///
+    /// The new string is written to a different string block than the one
+    /// the value points to, and the value is then atomically re-pointed to
+    /// it, so readers see either the old or the new string in full. The
+    /// value block's value field counts the updates, so that a reader the
+    /// string was set twice under can tell and read it again, as `Reader`
+    /// does.
+    pub fn set_val(&mut self, new_val: &str) -> Result<(), Error> {
+        self.check_no_indom()?;
+        write_str_val(&self.mmap_view, new_val)?;
+        self.val = new_val.to_owned();
+        Ok(())
+    }
 }
 
-pub struct Metric {
-    name: String,
-    item: u32,
-    sem: MetricSem,
-    indom: u32,
-    dim: u32,
-    shorttext: String,
-    longtext: String,
-    val: MetricType,
-    mmap_view: Option<MmapViewSync>
+impl Metric<Elapsed> {
+    /// Creates an elapsed time metric, which is always a counter of
+    /// microseconds
+    pub fn new_elapsed(
+        name: &str, item: u32,
+        shorthelp: &str, longhelp: &str) -> Result<Self, Error> {
+
+        Metric::with_type(
+            name, item, MetricType::Elapsed, MetricSem::Counter, Units::time(TimeScale::Microsec),
+            Elapsed::default(), shorthelp, longhelp)
+    }
+
+    /// Creates an elapsed time metric having one value per instance of
+    /// `indom`
+    pub fn new_elapsed_with_indom(
+        name: &str, item: u32, indom: &Indom,
+        shorthelp: &str, longhelp: &str) -> Result<Self, Error> {
+
+        let mut metric = Metric::new_elapsed(name, item, shorthelp, longhelp)?;
+        metric.set_indom(indom);
+        Ok(metric)
+    }
+
+    pub fn val(&self) -> Elapsed {
+        self.val
+    }
+
+    /// Starts an interval, unless one is already in progress
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.check_no_indom()?;
+        start_elapsed(&self.mmap_view, &mut self.val)
+    }
+
+    /// Stops the in-progress interval, adding its duration to the total
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.check_no_indom()?;
+        stop_elapsed(&self.mmap_view, &mut self.val)
+    }
 }
 
-impl Metric {
-    pub fn new(
-        name: &str, item: u32, sem: MetricSem,
-        indom: u32, dim: u32, init_val: MetricType,
-        shorthelp: &str, longhelp: &str) -> Self {
-        
-        assert!(name.len() < METRIC_NAME_MAX_LEN as usize);
-        assert!(shorthelp.len() < STRING_BLOCK_LEN as usize);
-        assert!(longhelp.len() < STRING_BLOCK_LEN as usize);
-
-        Metric {
-            name: name.to_owned(),
-            item: item,
-            sem: sem,
-            indom: indom,
-            dim: dim,
-            shorttext: shorthelp.to_owned(),
-            longtext: longhelp.to_owned(),
-            val: init_val,
-            mmap_view: None
+impl<T: MetricValue> MMVMetric for Metric<T> {
+    fn desc(&self) -> &MetricDesc {
+        &self.desc
+    }
+
+    // the values loaded from the file, as atomic handles may have
+    // changed them since
+    fn init_vals(&self) -> Vec<InitVal<'_>> {
+        match self.desc.indom {
+            Some(_) => self.instances.iter().map(|inst| InitVal::Raw(inst.val.to_raw())).collect(),
+            None => vec![InitVal::Raw(self.val.to_raw())]
         }
     }
 
-    pub fn val(&self) -> MetricType {
-        self.val.clone()
+    fn set_item(&mut self, item: u32) {
+        self.desc.item = Some(item);
     }
 
-    pub fn set_val(&mut self, new_val: MetricType) {
-        match self.mmap_view {
-            Some(ref mut mv) => {
-                let mut b_slice = unsafe { mv.as_mut_slice() };
-                match (self.val, new_val) {
-                    (MetricType::I64(_), MetricType::I64(new)) => {
-                        b_slice.write_i64::<LittleEndian>(new).unwrap()
-                    },
-                    (MetricType::F64(_), MetricType::F64(new)) => {
-                        b_slice.write_f64::<LittleEndian>(new).unwrap()
-                    },
-                    (_, _) => panic!("wrong metric type!")
-                }
-            },
-            None => panic!("metric not yet mapped!")
+    fn slots(&self) -> Vec<ValueSlot> {
+        self.slot_vec()
+    }
+
+    fn load_vals(&mut self, views: &[Option<&ValueView>]) {
+        self.load_vals_with(views, |mv| Some(T::from_raw(mv.read_raw())))
+    }
+}
+
+impl MMVMetric for Metric<Elapsed> {
+    fn desc(&self) -> &MetricDesc {
+        &self.desc
+    }
+
+    fn init_vals(&self) -> Vec<InitVal<'_>> {
+        match self.desc.indom {
+            Some(_) => self.instances.iter().map(|inst| InitVal::Elapsed(inst.val)).collect(),
+            None => vec![InitVal::Elapsed(self.val)]
         }
-        self.val = new_val;
+    }
+
+    fn set_item(&mut self, item: u32) {
+        self.desc.item = Some(item);
+    }
+
+    fn slots(&self) -> Vec<ValueSlot> {
+        self.slot_vec()
+    }
+
+    fn load_vals(&mut self, views: &[Option<&ValueView>]) {
+        self.load_vals_with(views, |mv| mv.read_elapsed().ok())
+    }
+}
+
+impl MMVMetric for Metric<String> {
+    fn desc(&self) -> &MetricDesc {
+        &self.desc
+    }
+
+    fn init_vals(&self) -> Vec<InitVal<'_>> {
+        match self.desc.indom {
+            Some(_) => self.instances.iter().map(|inst| InitVal::Str(&inst.val)).collect(),
+            None => vec![InitVal::Str(&self.val)]
+        }
+    }
+
+    fn set_item(&mut self, item: u32) {
+        self.desc.item = Some(item);
+    }
+
+    fn slots(&self) -> Vec<ValueSlot> {
+        self.slot_vec()
+    }
+
+    fn load_vals(&mut self, views: &[Option<&ValueView>]) {
+        self.load_vals_with(views, |mv| mv.read_str().ok())
     }
 }
 
+/// Writer of an MMV file
+///
+/// The file stays mapped for as long as the `MMV` is alive. Closing it,
+/// with `close` or by dropping it, flushes and unmaps the file, after
+/// which updating the metrics mapped into it fails with
+/// `Error::NotMapped`, while their values read as they were last.
 pub struct MMV {
     path: String,
     flags: MMVFlags,
     cluster_id: u32,
+    version: MMVVersion,
+    labels: Vec<Label>,
+    remove_on_close: bool,
+    warnings: Vec<String>,
+    // generation, writer and mapping of the last file written
+    generation: i64,
+    pid: i32,
+    mapping: Option<Arc<Mapping>>
+}
+
+// Generation numbers work like a seqlock. A writer sets generation1 to a
+// new value before changing the file and generation2 to match once done,
+// so a reader that finds them equal after reading saw a complete file.
+fn generation(header: &[u8], offset: u64) -> &AtomicI64 {
+    // the header is at the start of the page-aligned mapping, so the
+    // generations are 8-byte aligned
+    unsafe { &*(header[offset as usize..].as_ptr() as *const AtomicI64) }
+}
+
+// Changes generation1 of a mapped file, so that readers find it differs
+// from generation2 and know to open the file at the path again.
+fn mark_stale(mapping: &Mapping, gen: i64) {
+    let _ = mapping.with(|start| {
+        let header = unsafe { slice::from_raw_parts(start, HDR_LEN as usize) };
+        generation(header, GEN1_OFFSET).store(gen, Ordering::Release);
+    });
 }
 
 macro_rules! write_str_with_nul {
     ($x:expr, $y:expr) => {
-        $x.write($y.as_bytes()).unwrap();
-        $x.write(&[0]).unwrap();
+        $x.write_all($y.as_bytes())?;
+        $x.write_all(&[0])?;
+    }
+}
+
+fn write_toc_block(mmv: &mut Cursor<&mut [u8]>, sec_type: u32, n_entries: u64, offset: u64) -> io::Result<()> {
+    // section type
+    mmv.write_u32::<LittleEndian>(sec_type)?;
+    // no. of entries
+    mmv.write_u32::<LittleEndian>(n_entries as u32)?;
+    // section offset
+    mmv.write_u64::<LittleEndian>(offset)?;
+    Ok(())
+}
+
+// Offsets and sizes of each section of an MMV file
+struct Layout {
+    version: MMVVersion,
+    instance_block_len: u64,
+    metric_block_len: u64,
+    n_toc: u64,
+    n_instances: u64,
+    n_values: u64,
+    n_strings: u64,
+    n_labels: u64,
+    indom_section_offset: u64,
+    instance_section_offset: u64,
+    metric_section_offset: u64,
+    value_section_offset: u64,
+    string_section_offset: u64,
+    label_section_offset: u64,
+    mmv_size: u64
+}
+
+impl Layout {
+    fn new(version: MMVVersion, indoms: &[Indom], metrics: &[&mut dyn MMVMetric], n_labels: u64) -> Self {
+        let n_indoms = indoms.len() as u64;
+        let n_instances = indoms.iter()
+            .map(|indom| indom.instances.len() as u64).sum();
+        let n_metrics = metrics.len() as u64;
+        let n_values = metrics.iter().map(|m| m.desc().n_values()).sum();
+        let n_value_strings: u64 = metrics.iter().map(|m| m.desc().n_value_strings()).sum();
+        let (instance_block_len, metric_block_len, n_strings) = match version {
+            MMVVersion::V1 => (
+                INSTANCE_V1_BLOCK_LEN, METRIC_V1_BLOCK_LEN,
+                2*(n_indoms + n_metrics) + n_value_strings
+            ),
+            // names go into the string section
+            MMVVersion::V2 | MMVVersion::V3 => (
+                INSTANCE_V2_BLOCK_LEN, METRIC_V2_BLOCK_LEN,
+                2*(n_indoms + n_metrics) + n_instances + n_metrics + n_value_strings
+            )
+        };
+        let mut n_toc = 3;
+        if n_indoms > 0 { n_toc += 2; }
+        if n_labels > 0 { n_toc += 1; }
+
+        let indom_section_offset = HDR_LEN + n_toc*TOC_BLOCK_LEN;
+        let instance_section_offset = indom_section_offset + n_indoms*INDOM_BLOCK_LEN;
+        let metric_section_offset = instance_section_offset + n_instances*instance_block_len;
+        let value_section_offset = metric_section_offset + n_metrics*metric_block_len;
+        let string_section_offset = value_section_offset + n_values*VALUE_BLOCK_LEN;
+        let label_section_offset = string_section_offset + n_strings*STRING_BLOCK_LEN;
+
+        Layout {
+            version: version,
+            instance_block_len: instance_block_len,
+            metric_block_len: metric_block_len,
+            n_toc: n_toc,
+            n_instances: n_instances,
+            n_values: n_values,
+            n_strings: n_strings,
+            n_labels: n_labels,
+            indom_section_offset: indom_section_offset,
+            instance_section_offset: instance_section_offset,
+            metric_section_offset: metric_section_offset,
+            value_section_offset: value_section_offset,
+            string_section_offset: string_section_offset,
+            label_section_offset: label_section_offset,
+            mmv_size: label_section_offset + n_labels*LABEL_BLOCK_LEN
+        }
     }
 }
 
 impl MMV {
-    pub fn new(path: &str, flags: MMVFlags, cluster_id: u32) -> MMV {
-        MMV {
+    /// Creates an MMV file at `path`, failing if `cluster_id` doesn't fit
+    /// in the 12 bits of a PMID's cluster.
+    ///
+    /// Files with the `NOPREFIX` flag should have a non-zero cluster id,
+    /// as their metrics would otherwise clash with the MMV PMDA's own, and
+    /// `warnings` says so if they don't.
+    pub fn new(path: &str, flags: MMVFlags, cluster_id: u32) -> Result<MMV, Error> {
+        if cluster_id > CLUSTER_MAX {
+            return Err(Error::ClusterIdOutOfRange(cluster_id));
+        }
+        let mut warnings = Vec::new();
+        if flags.contains(NOPREFIX) && cluster_id == 0 {
+            warnings.push(format!(
+                "{} has the NOPREFIX flag but no cluster id, \
+                 so the MMV PMDA may ignore its metrics", path));
+        }
+        Ok(MMV {
             path: path.to_owned(),
             flags: flags,
             cluster_id: cluster_id,
+            version: MMVVersion::V1,
+            labels: Vec::new(),
+            remove_on_close: false,
+            warnings: warnings,
+            generation: 0,
+            pid: 0,
+            mapping: None
+        })
+    }
+
+    /// Creates an MMV file named `client` in the `mmv` directory under
+    /// `$PCP_TMP_DIR`, where the MMV PMDA looks for them.
+    ///
+    /// `$PCP_TMP_DIR` is read from the environment, then from `$PCP_CONF`
+    /// or `/etc/pcp.conf`, and defaults to `/var/lib/pcp/tmp`. The `mmv`
+    /// directory is created if it doesn't exist.
+    pub fn for_client(client: &str, flags: MMVFlags, cluster_id: u32) -> Result<MMV, Error> {
+        if client.is_empty() || client == "." || client == ".." ||
+            client.contains(std::path::is_separator) || client.contains('\0') {
+            return Err(Error::InvalidClientName(client.to_owned()));
         }
+        let path = config::mmv_dir()?.join(client);
+        MMV::new(&path.to_string_lossy(), flags, cluster_id)
     }
 
-    pub fn map(&self, metrics: &mut [&mut Metric]) {
-        let mut file = OpenOptions::new()
-            .read(true).write(true).open(&self.path).unwrap();
-        let n_metrics = metrics.len() as u64;
-        let mmv_size =
-            HDR_LEN + 3*TOC_BLOCK_LEN +
-            n_metrics*(METRIC_BLOCK_LEN + VALUE_BLOCK_LEN + 2*STRING_BLOCK_LEN);
-        for _ in 0..mmv_size {
-            file.write(&[0]).unwrap();
+    /// Sets the minimum version of the MMV file to write.
+    ///
+    /// A version 1 file is written as version 2 anyway if any metric or
+    /// instance name is too long to be stored inline, and any file with
+    /// labels is written as version 3.
+    pub fn set_version(&mut self, version: MMVVersion) {
+        self.version = version;
+    }
+
+    pub fn version(&self) -> MMVVersion {
+        self.version
+    }
+
+    /// Problems with the settings of the file that don't keep it from
+    /// being written, but may keep the MMV PMDA from exporting its metrics
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Adds a label applying to every metric in the file
+    pub fn add_label<V: Into<LabelValue>>(&mut self, name: &str, value: V) -> Result<(), Error> {
+        add_label(&mut self.labels, name, value.into())
+    }
+
+    /// Writes the metrics to the MMV file and points them at their values
+    /// in it.
+    ///
+    /// The file is built under a temporary name in the same directory and
+    /// renamed into place once complete, so a reader never sees a
+    /// partially written file at the final path.
+    ///
+    /// Mapping again re-publishes the file with a new generation number,
+    /// and marks the previous file as stale so that readers still mapping
+    /// it know to open the file at the path again.
+    ///
+    /// A child process forked after the file was mapped can't map it
+    /// again, as that would replace its parent's file, and has to use
+    /// `remap_after_fork` instead.
+    pub fn map(&mut self, metrics: &mut [&mut dyn MMVMetric]) -> Result<(), Error> {
+        if self.is_inherited() {
+            return Err(Error::InheritedFile(self.path.clone()));
+        }
+        self.publish(metrics)
+    }
+
+    /// Re-creates the MMV file at `path` in a child process forked after
+    /// the file was mapped, with the child's pid and a new generation
+    /// number, and points the metrics at their values in it. The values
+    /// start out as they were at the fork.
+    ///
+    /// Otherwise, a child writes to its parent's file, which is shared
+    /// between them. That's safe for numeric metrics, whose values are
+    /// updated atomically, but not for string or elapsed time metrics.
+    /// The file still carries the parent's pid, so with the `PROCESS`
+    /// flag it lives as long as the parent, and a child closing its `MMV`
+    /// neither removes it nor marks it stale.
+    ///
+    /// In the process that mapped the file, this moves the metrics to a
+    /// new file at `path`, leaving the old one marked stale.
+    pub fn remap_after_fork(&mut self, path: &str, metrics: &mut [&mut dyn MMVMetric]) -> Result<(), Error> {
+        if self.is_inherited() {
+            if path == self.path {
+                return Err(Error::InheritedFile(self.path.clone()));
+            }
+            // the values are then read back from the copy kept of the
+            // unmapped file, leaving the parent's file untouched
+            if let Some(mapping) = self.mapping.take() {
+                let _ = mapping.close();
+            }
+        }
+        self.path = path.to_owned();
+        self.publish(metrics)
+    }
+
+    /// Whether the MMV file was mapped by a parent of this process, i.e.
+    /// the process forked since
+    pub fn is_inherited(&self) -> bool {
+        self.mapping.is_some() && self.pid != getpid()
+    }
+
+    /// Resumes writing to the existing MMV file at the path, e.g. one left
+    /// by a previous run of the process, instead of writing it anew. The
+    /// metrics carry on from their values in the file, so counters don't
+    /// appear to reset.
+    ///
+    /// The file must have been written by `map` with the same flags and
+    /// cluster id, and with metrics of the same names, items, types,
+    /// semantics, units and instances as `metrics`, or else resuming fails
+    /// with `Error::SchemaMismatch` describing the difference. Metrics
+    /// without an item take the one given to the metric of the same name
+    /// in the file. The file is given this process's pid and a new
+    /// generation number, and elapsed time metrics that were running
+    /// resume stopped.
+    ///
+    /// Resuming a file whose process is still alive fails with
+    /// `Error::FileInUse`.
+    pub fn resume(&mut self, metrics: &mut [&mut dyn MMVMetric]) -> Result<(), Error> {
+        if self.is_inherited() {
+            return Err(Error::InheritedFile(self.path.clone()));
+        }
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let reader = Reader::from_file(&file)?;
+        // non-positive pids name process groups rather than a process
+        let pid = reader.pid();
+        if pid > 0 && pid != getpid() && is_alive(pid) {
+            return Err(Error::FileInUse(self.path.clone(), pid));
+        }
+        // metrics without an item keep the one they were given in the file
+        let items = Self::assign_items(metrics, metrics.iter()
+            .map(|m| m.desc().item.or_else(|| reader.metric(&m.desc().name).map(|file_m| file_m.item)))
+            .collect())?;
+        self.check_schema(&reader, metrics, &items)?;
+
+        let mmap = Mmap::open(&file, Protection::ReadWrite)?;
+        let mapping = Arc::new(Mapping::new(mmap.into_view_sync()));
+        let views = metrics.iter()
+            .map(|m| Self::resumed_views(&mapping, &reader, m.desc()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        for (m, views) in metrics.iter().zip(&views) {
+            for view in views {
+                match m.desc().mtype {
+                    // intervals in progress when the file was left were
+                    // never stopped, so the time since they started isn't
+                    // counted
+                    MetricType::Elapsed =>
+                        view.with_fields(|_, extra| extra.store(0, Ordering::Release))?,
+                    // a string write cut short leaves the value pointing
+                    // to the string from before it, but its count odd
+                    MetricType::String => view.with_fields(|seq, _| {
+                        let count = seq.load(Ordering::Relaxed);
+                        if count & 1 != 0 {
+                            seq.store(count.wrapping_add(1), Ordering::Release);
+                        }
+                    })?,
+                    _ => {}
+                }
+            }
+        }
+
+        let gen = std::cmp::max(
+            time::now().to_timespec().sec,
+            std::cmp::max(reader.generation(), self.generation) + 1);
+        mapping.with(|start| {
+            let header = unsafe { slice::from_raw_parts_mut(start, HDR_LEN as usize) };
+            generation(header, GEN1_OFFSET).store(gen, Ordering::Relaxed);
+            fence(Ordering::Release);
+            // pid
+            LittleEndian::write_i32(&mut header[32..36], getpid());
+            generation(header, GEN2_OFFSET).store(gen, Ordering::Release);
+        })?;
+
+        let slots: Vec<_> = metrics.iter().map(|m| m.slots()).collect();
+        let mut guards = Self::lock_slots(&slots);
+        for (m, views) in metrics.iter_mut().zip(&views) {
+            m.load_vals(&views.iter().map(Some).collect::<Vec<_>>());
+        }
+        Self::point_slots(&mut guards, views);
+        drop(guards);
+        for (m, &item) in metrics.iter_mut().zip(&items) {
+            m.set_item(item);
+        }
+        if let Some(old_mapping) = self.mapping.take() {
+            let _ = old_mapping.close();
+        }
+        self.generation = gen;
+        self.pid = getpid();
+        self.mapping = Some(mapping);
+        Ok(())
+    }
+
+    fn check_schema(&self, reader: &Reader, metrics: &[&mut dyn MMVMetric], items: &[u32]) -> Result<(), Error> {
+        let mismatch = |what: String| Err(Error::SchemaMismatch(what));
+        if reader.flags() != self.flags {
+            return mismatch(format!("flags are {:?}, not {:?}", reader.flags(), self.flags));
+        }
+        if reader.cluster_id() != self.cluster_id {
+            return mismatch(format!("cluster id is {}, not {}", reader.cluster_id(), self.cluster_id));
+        }
+        if reader.metrics().len() != metrics.len() {
+            return mismatch(format!("file has {} metrics, not {}", reader.metrics().len(), metrics.len()));
+        }
+
+        for (m, &item) in metrics.iter().zip(items) {
+            let desc = m.desc();
+            let name = &desc.name;
+            let file_m = match reader.metric(name) {
+                Some(file_m) => file_m,
+                None => return mismatch(format!("no metric named \"{}\" in the file", name))
+            };
+            if file_m.item != item {
+                return mismatch(format!("item of \"{}\" is {}, not {}", name, file_m.item, item));
+            }
+            if file_m.mtype != desc.mtype {
+                return mismatch(format!("type of \"{}\" is {:?}, not {:?}", name, file_m.mtype, desc.mtype));
+            }
+            if file_m.sem != desc.sem {
+                return mismatch(format!("semantics of \"{}\" are {:?}, not {:?}", name, file_m.sem, desc.sem));
+            }
+            if file_m.units != desc.units {
+                return mismatch(format!("units of \"{}\" are {:?}, not {:?}", name, file_m.units, desc.units));
+            }
+
+            let serial = desc.indom.as_ref().map(|indom| indom.serial);
+            if file_m.indom != serial {
+                let serial_str = |serial: Option<u32>| serial.map_or("none".to_owned(), |s| s.to_string());
+                return mismatch(format!(
+                    "instance domain of \"{}\" is {}, not {}",
+                    name, serial_str(file_m.indom), serial_str(serial)));
+            }
+            if let Some(ref indom) = desc.indom {
+                let mut instances = indom.instances.clone();
+                let mut file_instances: Vec<(i32, String)> = reader.indom(indom.serial)
+                    .map_or(Vec::new(), |file_indom| file_indom.instances.iter()
+                        .map(|inst| (inst.id, inst.name.clone()))
+                        .collect());
+                instances.sort();
+                file_instances.sort();
+                if file_instances != instances {
+                    return mismatch(format!(
+                        "instances of indom {} are {:?}, not {:?}",
+                        indom.serial, file_instances, instances));
+                }
+            }
         }
+        Ok(())
+    }
+
+    // Views of the value blocks of a metric in a file being resumed, in
+    // the order `split_mmap_views` gives them.
+    fn resumed_views(mapping: &Arc<Mapping>, reader: &Reader, desc: &MetricDesc) -> Result<Vec<ValueView>, Error> {
+        let instances: Vec<Option<&str>> = match desc.indom {
+            Some(ref indom) => indom.instances.iter().map(|&(_, ref name)| Some(&name[..])).collect(),
+            None => vec![None]
+        };
+        let (n_strings, string_section_offset) = reader.toc().iter()
+            .find(|e| e.section == Section::Strings)
+            .map_or((0, 0), |e| (e.n_entries as u64, e.offset));
+        let string_section_end = string_section_offset + n_strings*STRING_BLOCK_LEN;
+
+        let mut views = Vec::new();
+        for (i, instance) in instances.into_iter().enumerate() {
+            let offset = match reader.value_offset(&desc.name, instance) {
+                Some(offset) => offset,
+                None => return Err(Error::SchemaMismatch(format!(
+                    "no value of \"{}\"{} in the file", desc.name,
+                    instance.map_or(String::new(), |inst| format!(" for instance \"{}\"", inst)))))
+            };
+            let mut view = ValueView {
+                mapping: mapping.clone(),
+                offset: offset,
+                strings_offset: None
+            };
+            if desc.mtype == MetricType::String {
+                // write_mmv puts the pairs of string blocks of a metric's
+                // values right after its help text
+                let metric_offset = mapping.read_u64(offset + 16);
+                let fields_offset = metric_offset + match reader.version() {
+                    MMVVersion::V1 => METRIC_NAME_MAX_LEN,
+                    _ => 8
+                };
+                let longhelp_offset = mapping.read_u64(fields_offset + 32);
+                let strings_offset = longhelp_offset + STRING_BLOCK_LEN + 2*STRING_BLOCK_LEN*i as u64;
+                let cur_offset = mapping.read_u64(offset + 8);
+                if strings_offset < string_section_offset ||
+                    strings_offset + 2*STRING_BLOCK_LEN > string_section_end ||
+                    (cur_offset != strings_offset && cur_offset != strings_offset + STRING_BLOCK_LEN) {
+                    return Err(Error::SchemaMismatch(format!(
+                        "string blocks of \"{}\" aren't laid out as map lays them out", desc.name)));
+                }
+                view.strings_offset = Some(strings_offset);
+            }
+            views.push(view);
+        }
+        Ok(views)
+    }
+
+    fn publish(&mut self, metrics: &mut [&mut dyn MMVMetric]) -> Result<(), Error> {
+        // the values are read out of the file the metrics are mapped into,
+        // with their atomic handles held off until they are moved to the
+        // new file
+        let slots: Vec<_> = metrics.iter().map(|m| m.slots()).collect();
+        let mut guards = Self::lock_slots(&slots);
+        for (m, guards) in metrics.iter_mut().zip(&guards) {
+            m.load_vals(&guards.iter().map(|guard| guard.as_ref()).collect::<Vec<_>>());
+        }
+
+        let items = self.items(metrics)?;
+        let indoms = Self::indoms(metrics)?;
+        let labels = self.label_blocks(&indoms, metrics, &items);
+        let layout = Layout::new(
+            self.file_version(&indoms, metrics, &labels), &indoms, metrics, labels.len() as u64);
+
+        // strictly increasing even when re-publishing within a second
+        let gen = std::cmp::max(time::now().to_timespec().sec, self.generation + 1);
+        let tmp_path = self.tmp_path();
+        let mmap = match self.write_tmp(&tmp_path, gen, &layout, &indoms, metrics, &items, &labels) {
+            Ok(mmap) => mmap,
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err.into());
+            }
+        };
+        // the mapping stays valid across the rename as it refers to the
+        // file itself, not its name
+        if let Err(err) = fs::rename(&tmp_path, &self.path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+
+        let mapping = Arc::new(Mapping::new(mmap.into_view_sync()));
+        Self::point_slots(&mut guards, Self::split_mmap_views(&mapping, &layout, metrics));
+        drop(guards);
+        for (m, &item) in metrics.iter_mut().zip(&items) {
+            m.set_item(item);
+        }
+        if let Some(old_mapping) = self.mapping.take() {
+            mark_stale(&old_mapping, gen);
+            // nothing reads the stale file back, so a failed flush of it
+            // loses nothing
+            let _ = old_mapping.close();
+        }
+        self.generation = gen;
+        self.pid = getpid();
+        self.mapping = Some(mapping);
+        Ok(())
+    }
+
+    /// Sets whether closing the `MMV` also removes its file, so that the
+    /// MMV PMDA stops exporting the metrics. Files are kept by default.
+    pub fn set_remove_on_close(&mut self, remove_on_close: bool) {
+        self.remove_on_close = remove_on_close;
+    }
+
+    /// Flushes and unmaps the MMV file, removing it if set to with
+    /// `set_remove_on_close` and it wasn't mapped by a parent process.
+    /// Updating the metrics mapped into it, or their atomic handles, fails
+    /// with `Error::NotMapped` from then on.
+    ///
+    /// Dropping the `MMV` does the same, but ignores any error.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        let mapping = match self.mapping.take() {
+            Some(mapping) => mapping,
+            None => return Ok(())
+        };
+        let removed = if self.remove_on_close && self.pid == getpid() {
+            // readers that still have the file mapped see it as stale
+            mark_stale(&mapping, self.generation + 1);
+            fs::remove_file(&self.path)
+        } else {
+            Ok(())
+        };
+        let closed = mapping.close();
+        removed?;
+        closed?;
+        Ok(())
+    }
+
+    // hidden file next to the final path, so that it is on the same
+    // filesystem for the rename and is skipped by the MMV PMDA meanwhile
+    fn tmp_path(&self) -> PathBuf {
+        let path = Path::new(&self.path);
+        let file_name = path.file_name().map_or(
+            String::new(), |name| name.to_string_lossy().into_owned());
+        path.with_file_name(format!(".{}.{}.tmp", file_name, getpid()))
+    }
+
+    fn write_tmp(
+        &self, tmp_path: &Path, gen: i64, layout: &Layout, indoms: &[Indom],
+        metrics: &[&mut dyn MMVMetric], items: &[u32], labels: &[LabelBlock]) -> io::Result<Mmap> {
+
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(tmp_path)?;
+        file.set_len(layout.mmv_size)?;
 
         let mut mmap = Mmap::open_with_offset(
-            &file, Protection::ReadWrite, 0, mmv_size as usize).unwrap();
-        self.write_mmv(&mut mmap, metrics);
-        self.split_mmap_views(mmap, metrics)
+            &file, Protection::ReadWrite, 0, layout.mmv_size as usize)?;
+        self.write_mmv(&mut mmap, gen, layout, indoms, metrics, items, labels)?;
+        mmap.flush()?;
+        Ok(mmap)
+    }
+
+    fn file_version(&self, indoms: &[Indom], metrics: &[&mut dyn MMVMetric], labels: &[LabelBlock]) -> MMVVersion {
+        if !labels.is_empty() {
+            return std::cmp::max(self.version, MMVVersion::V3);
+        }
+
+        let names_fit_v1 =
+            metrics.iter().all(|m| m.desc().name.len() < METRIC_NAME_MAX_LEN as usize) &&
+            indoms.iter().flat_map(|indom| indom.instances.iter())
+                .all(|&(_, ref name)| name.len() < INSTANCE_NAME_MAX_LEN as usize);
+        if names_fit_v1 { self.version } else { std::cmp::max(self.version, MMVVersion::V2) }
+    }
+
+    fn label_blocks(&self, indoms: &[Indom], metrics: &[&mut dyn MMVMetric], items: &[u32]) -> Vec<LabelBlock> {
+
+        let mut blocks = Vec::new();
+        for l in &self.labels {
+            blocks.push(LabelBlock {
+                flags: LABEL_CLUSTER, identity: self.cluster_id,
+                internal: IN_NULL, payload: l.payload.clone()
+            });
+        }
+        for indom in indoms {
+            let indom_labels = indom.labels();
+            for l in &indom_labels.labels {
+                blocks.push(LabelBlock {
+                    flags: LABEL_INDOM, identity: indom.serial,
+                    internal: IN_NULL, payload: l.payload.clone()
+                });
+            }
+            for &(id, ref labels) in &indom_labels.instance_labels {
+                for l in labels {
+                    blocks.push(LabelBlock {
+                        flags: LABEL_INSTANCES, identity: indom.serial,
+                        internal: id, payload: l.payload.clone()
+                    });
+                }
+            }
+        }
+        for (m, &item) in metrics.iter().zip(items) {
+            for l in &m.desc().labels {
+                blocks.push(LabelBlock {
+                    flags: LABEL_ITEM, identity: item,
+                    internal: IN_NULL, payload: l.payload.clone()
+                });
+            }
+        }
+        blocks
+    }
+
+    // Item of each metric: the one it was given or assigned when mapped
+    // before, else that of the metric of the same name in the file already
+    // at the path, e.g. one left by a previous run, else the hash of its
+    // name. Mapping keeps the items it assigns, so that items never move
+    // once given out, and metrics whose names hash to the same item fail
+    // with `Error::DuplicateItem` rather than take turns at it.
+    fn items(&self, metrics: &[&mut dyn MMVMetric]) -> Result<Vec<u32>, Error> {
+        // a file that can't be read is written over without taking items
+        // from it
+        let reader = if metrics.iter().any(|m| m.desc().item.is_none()) {
+            Reader::open(&self.path).ok()
+        } else {
+            None
+        };
+        Self::assign_items(metrics, metrics.iter()
+            .map(|m| m.desc().item.or_else(|| reader.as_ref()
+                .and_then(|reader| reader.metric(&m.desc().name))
+                .map(|file_m| file_m.item)))
+            .collect())
+    }
+
+    // `items` with the item of each metric given, if it has one
+    fn assign_items(metrics: &[&mut dyn MMVMetric], items: Vec<Option<u32>>) -> Result<Vec<u32>, Error> {
+        let mut used = vec![false; ITEM_MAX as usize + 1];
+        let mut assigned = Vec::with_capacity(metrics.len());
+        for (i, (m, item)) in metrics.iter().zip(items).enumerate() {
+            let desc = m.desc();
+            if metrics[..i].iter().any(|prev| prev.desc().name == desc.name) {
+                return Err(Error::DuplicateName(desc.name.clone()));
+            }
+            let item = item.unwrap_or_else(|| name_hash(&desc.name) % (ITEM_MAX + 1));
+            if used[item as usize] {
+                return Err(Error::DuplicateItem(item));
+            }
+            used[item as usize] = true;
+            assigned.push(item);
+        }
+        Ok(assigned)
     }
 
-    fn write_mmv(&self, mmap: &mut Mmap, metrics: &[&mut Metric]) {
+    // distinct instance domains of the metrics, in order of first use
+    fn indoms(metrics: &[&mut dyn MMVMetric]) -> Result<Vec<Indom>, Error> {
+        let mut indoms: Vec<Indom> = Vec::new();
+        for m in metrics.iter() {
+            if let Some(ref indom) = m.desc().indom {
+                match indoms.iter().find(|i| i.serial == indom.serial) {
+                    Some(i) => if i != indom {
+                        return Err(Error::ConflictingIndoms(indom.serial));
+                    },
+                    None => indoms.push(indom.clone())
+                }
+            }
+        }
+        Ok(indoms)
+    }
+
+    fn write_mmv(
+        &self, mmap: &mut Mmap, gen: i64, layout: &Layout, indoms: &[Indom],
+        metrics: &[&mut dyn MMVMetric], items: &[u32], labels: &[LabelBlock]) -> io::Result<()> {
+
         let mut mmv = Cursor::new(unsafe { mmap.as_mut_slice() });
+        let n_indoms = indoms.len() as u64;
         let n_metrics = metrics.len() as u64;
 
         // MMV\0
         write_str_with_nul!(mmv, "MMV");
         // version
-        mmv.write_u32::<LittleEndian>(1).unwrap();
+        mmv.write_u32::<LittleEndian>(layout.version as u32)?;
         // generation1
-        let gen = time::now().to_timespec().sec;
-        mmv.write_i64::<LittleEndian>(gen).unwrap();
-        let gen2pos = mmv.position();
-        mmv.write_i64::<LittleEndian>(0).unwrap();
+        mmv.write_i64::<LittleEndian>(gen)?;
+        // generation2, set once the rest of the file is written
+        mmv.write_i64::<LittleEndian>(0)?;
         // no. of toc blocks
-        mmv.write_i32::<LittleEndian>(3).unwrap();
+        mmv.write_i32::<LittleEndian>(layout.n_toc as i32)?;
         // flags
-        mmv.write_u32::<LittleEndian>(self.flags.bits()).unwrap();
+        mmv.write_u32::<LittleEndian>(self.flags.bits())?;
         // pid
-        mmv.write_i32::<LittleEndian>(getpid()).unwrap();
+        mmv.write_i32::<LittleEndian>(getpid())?;
         // cluster id
-        mmv.write_u32::<LittleEndian>(self.cluster_id).unwrap();
+        mmv.write_u32::<LittleEndian>(self.cluster_id)?;
 
+        if n_indoms > 0 {
+            // indoms TOC block
+            write_toc_block(&mut mmv, INDOM_TOC_TYPE, n_indoms, layout.indom_section_offset)?;
+            // instances TOC block
+            write_toc_block(&mut mmv, INSTANCE_TOC_TYPE, layout.n_instances, layout.instance_section_offset)?;
+        }
         // metrics TOC block
-        // section type
-        mmv.write_u32::<LittleEndian>(3).unwrap();
-        // no. of entries
-        mmv.write_u32::<LittleEndian>(n_metrics as u32).unwrap();
-        // section offset
-        let metric_section_offset: u64 = HDR_LEN + TOC_BLOCK_LEN*3;
-        mmv.write_u64::<LittleEndian>(metric_section_offset as u64).unwrap();
-
+        write_toc_block(&mut mmv, METRIC_TOC_TYPE, n_metrics, layout.metric_section_offset)?;
         // values TOC block
-        // section type
-        mmv.write_u32::<LittleEndian>(4).unwrap();
-        // no. of entries
-        mmv.write_u32::<LittleEndian>(n_metrics as u32).unwrap();
-        // section offset
-        let value_section_offset = metric_section_offset + METRIC_BLOCK_LEN*n_metrics;
-        mmv.write_u64::<LittleEndian>(value_section_offset).unwrap();
-
+        write_toc_block(&mut mmv, VALUE_TOC_TYPE, layout.n_values, layout.value_section_offset)?;
         // strings TOC block
-        // section type
-        mmv.write_u32::<LittleEndian>(5).unwrap();
-        // no. of entries
-        mmv.write_u32::<LittleEndian>(2*n_metrics as u32).unwrap();
-        // section offset
-        let string_section_offset = value_section_offset + VALUE_BLOCK_LEN*n_metrics;
-        mmv.write_u64::<LittleEndian>(string_section_offset).unwrap();
+        write_toc_block(&mut mmv, STRING_TOC_TYPE, layout.n_strings, layout.string_section_offset)?;
+        if layout.n_labels > 0 {
+            // labels TOC block
+            write_toc_block(&mut mmv, LABEL_TOC_TYPE, layout.n_labels, layout.label_section_offset)?;
+        }
+
+        let mut string_block_offset = layout.string_section_offset;
+
+        // indom, instance blocks
+        let mut instance_block_offset = layout.instance_section_offset;
+        for (i, indom) in indoms.iter().enumerate() {
+            let indom_block_offset = layout.indom_section_offset + i as u64*INDOM_BLOCK_LEN;
+            mmv.set_position(indom_block_offset);
+            // serial
+            mmv.write_u32::<LittleEndian>(indom.serial)?;
+            // no. of instances
+            mmv.write_u32::<LittleEndian>(indom.instances.len() as u32)?;
+            // offset to first instance block
+            mmv.write_u64::<LittleEndian>(instance_block_offset)?;
+            // short help offset
+            mmv.write_u64::<LittleEndian>(string_block_offset)?;
+            // long help offset
+            mmv.write_u64::<LittleEndian>(string_block_offset + STRING_BLOCK_LEN)?;
+
+            // short and long help
+            mmv.set_position(string_block_offset);
+            write_str_with_nul!(mmv, indom.shorttext);
+            mmv.set_position(string_block_offset + STRING_BLOCK_LEN);
+            write_str_with_nul!(mmv, indom.longtext);
+            string_block_offset += 2*STRING_BLOCK_LEN;
+
+            for &(id, ref name) in &indom.instances {
+                mmv.set_position(instance_block_offset);
+                // offset to indom block
+                mmv.write_u64::<LittleEndian>(indom_block_offset)?;
+                // zero pad
+                mmv.write_u32::<LittleEndian>(0)?;
+                // internal id
+                mmv.write_i32::<LittleEndian>(id)?;
+                // external id
+                match layout.version {
+                    MMVVersion::V1 => {
+                        write_str_with_nul!(mmv, name);
+                    },
+                    MMVVersion::V2 | MMVVersion::V3 => {
+                        mmv.write_u64::<LittleEndian>(string_block_offset)?;
+                        mmv.set_position(string_block_offset);
+                        write_str_with_nul!(mmv, name);
+                        string_block_offset += STRING_BLOCK_LEN;
+                    }
+                }
+                instance_block_offset += layout.instance_block_len;
+            }
+        }
 
         // metric, value, string blocks
+        let mut value_block_offset = layout.value_section_offset;
         for (i, m) in metrics.iter().enumerate() {
             let i = i as u64;
-            
+            let desc = m.desc();
+
             // metric block
-            let metric_block_offset: u64 = metric_section_offset + i*METRIC_BLOCK_LEN;
+            let metric_block_offset: u64 = layout.metric_section_offset + i*layout.metric_block_len;
             mmv.set_position(metric_block_offset);
             // name
-            write_str_with_nul!(mmv, m.name);
-            mmv.set_position(metric_block_offset + METRIC_NAME_MAX_LEN);
+            match layout.version {
+                MMVVersion::V1 => {
+                    write_str_with_nul!(mmv, desc.name);
+                    mmv.set_position(metric_block_offset + METRIC_NAME_MAX_LEN);
+                },
+                MMVVersion::V2 | MMVVersion::V3 => {
+                    mmv.write_u64::<LittleEndian>(string_block_offset)?;
+                    let name_end = mmv.position();
+                    mmv.set_position(string_block_offset);
+                    write_str_with_nul!(mmv, desc.name);
+                    mmv.set_position(name_end);
+                    string_block_offset += STRING_BLOCK_LEN;
+                }
+            }
             // item
-            mmv.write_u32::<LittleEndian>(m.item).unwrap();
+            mmv.write_u32::<LittleEndian>(items[i as usize])?;
             // type
-            match m.val {
-                MetricType::I64(_) => mmv.write_u32::<LittleEndian>(2).unwrap(),
-                MetricType::F64(_) => mmv.write_u32::<LittleEndian>(5).unwrap(),
-            }
+            mmv.write_u32::<LittleEndian>(desc.mtype as u32)?;
             // sem
-            mmv.write_u32::<LittleEndian>(m.sem as u32).unwrap();
-            // dim
-            mmv.write_u32::<LittleEndian>(m.dim).unwrap();
+            mmv.write_u32::<LittleEndian>(desc.sem as u32)?;
+            // units
+            mmv.write_u32::<LittleEndian>(desc.units.to_raw())?;
             // indom
-            mmv.write_u32::<LittleEndian>(m.indom).unwrap();
+            let indom_serial = desc.indom.as_ref().map_or(0, |indom| indom.serial);
+            mmv.write_u32::<LittleEndian>(indom_serial)?;
             // zero pad
-            mmv.write_u32::<LittleEndian>(0).unwrap();
-            // short and long help offset
-            let shorthelp_offset_offset = mmv.position();
-            let longhelp_offset_offset = mmv.position() + 8;
+            mmv.write_u32::<LittleEndian>(0)?;
+            // short help offset
+            let help_offset = string_block_offset;
+            mmv.write_u64::<LittleEndian>(help_offset)?;
+            // long help offset
+            mmv.write_u64::<LittleEndian>(help_offset + STRING_BLOCK_LEN)?;
+            string_block_offset += 2*STRING_BLOCK_LEN;
 
             // value blocks
-            let value_block_offset = value_section_offset + i*VALUE_BLOCK_LEN;
-            mmv.set_position(value_block_offset);
-            // value
-            match m.val {
-                MetricType::I64(x) => mmv.write_i64::<LittleEndian>(x).unwrap(),
-                MetricType::F64(x) => mmv.write_u64::<LittleEndian>(unsafe {
-                    transmute::<f64, u64>(x)
-                }).unwrap(),
-            }
-            // extra
-            mmv.write_u64::<LittleEndian>(0).unwrap();
-            // offset to metric block
-            mmv.write_u64::<LittleEndian>(metric_block_offset).unwrap();
-            // offset to instance block
-            mmv.write_u64::<LittleEndian>(0).unwrap();
-
-            // string block
-            let string_block_offset = string_section_offset + i*2*STRING_BLOCK_LEN;
-            mmv.set_position(string_block_offset);
+            let instance_offsets: Vec<u64> = match desc.indom {
+                Some(ref indom) => indom.instances.iter()
+                    .map(|&(id, _)| Self::instance_block_offset(layout, indoms, indom.serial, id))
+                    .collect(),
+                None => vec![0]
+            };
+            for (init_val, instance_offset) in m.init_vals().into_iter().zip(instance_offsets) {
+                mmv.set_position(value_block_offset);
+                match init_val {
+                    InitVal::Raw(raw_val) => {
+                        // value
+                        mmv.write_u64::<LittleEndian>(raw_val)?;
+                        // extra
+                        mmv.write_u64::<LittleEndian>(0)?;
+                    },
+                    InitVal::Elapsed(elapsed) => {
+                        // value
+                        mmv.write_i64::<LittleEndian>(elapsed.total)?;
+                        // extra, i.e. negated start of the in-progress
+                        // interval
+                        mmv.write_i64::<LittleEndian>(elapsed.start.wrapping_neg())?;
+                    },
+                    InitVal::Str(s) => {
+                        // value, i.e. count of writes to the string
+                        mmv.write_u64::<LittleEndian>(0)?;
+                        // extra, i.e. offset to string block
+                        mmv.write_u64::<LittleEndian>(string_block_offset)?;
+                        let value_end = mmv.position();
+                        // string block, followed by the spare string block
+                        // used for updates
+                        mmv.set_position(string_block_offset);
+                        write_str_with_nul!(mmv, s);
+                        mmv.set_position(value_end);
+                        string_block_offset += 2*STRING_BLOCK_LEN;
+                    }
+                }
+                // offset to metric block
+                mmv.write_u64::<LittleEndian>(metric_block_offset)?;
+                // offset to instance block
+                mmv.write_u64::<LittleEndian>(instance_offset)?;
+                value_block_offset += VALUE_BLOCK_LEN;
+            }
+
+            // string blocks
             // short help
-            let shorthelp_offset = mmv.position();
-            mmv.set_position(shorthelp_offset_offset);
-            mmv.write_u64::<LittleEndian>(shorthelp_offset).unwrap();
-            mmv.set_position(shorthelp_offset);
-            write_str_with_nul!(mmv, m.shorttext);
+            mmv.set_position(help_offset);
+            write_str_with_nul!(mmv, desc.shorttext);
             // long help
-            let longhelp_offset = string_block_offset + STRING_BLOCK_LEN;
-            mmv.set_position(longhelp_offset_offset);
-            mmv.write_u64::<LittleEndian>(longhelp_offset).unwrap();
-            mmv.set_position(longhelp_offset);
-            write_str_with_nul!(mmv, m.longtext);
+            mmv.set_position(help_offset + STRING_BLOCK_LEN);
+            write_str_with_nul!(mmv, desc.longtext);
+        }
+
+        // label blocks
+        for (i, l) in labels.iter().enumerate() {
+            mmv.set_position(layout.label_section_offset + i as u64*LABEL_BLOCK_LEN);
+            // flags
+            mmv.write_u32::<LittleEndian>(l.flags)?;
+            // identity
+            mmv.write_u32::<LittleEndian>(l.identity)?;
+            // internal instance id
+            mmv.write_i32::<LittleEndian>(l.internal)?;
+            // payload
+            write_str_with_nul!(mmv, l.payload);
         }
 
         // unlock header
-        mmv.set_position(gen2pos);
-        mmv.write_i64::<LittleEndian>(gen).unwrap();
+        generation(mmv.into_inner(), GEN2_OFFSET).store(gen, Ordering::Release);
+        Ok(())
     }
 
-    fn split_mmap_views(&self, mmap: Mmap, metrics: &mut [&mut Metric]) {
-        let n_metrics = metrics.len() as u64;
-        let metric_section_offset = HDR_LEN as usize + (TOC_BLOCK_LEN as usize * 3);
-        let value_section_offset =
-            metric_section_offset +
-            METRIC_BLOCK_LEN as usize * n_metrics as usize;
+    fn instance_block_offset(layout: &Layout, indoms: &[Indom], serial: u32, id: i32) -> u64 {
+        let mut offset = layout.instance_section_offset;
+        for indom in indoms {
+            for &(inst_id, _) in &indom.instances {
+                if indom.serial == serial && inst_id == id {
+                    return offset;
+                }
+                offset += layout.instance_block_len;
+            }
+        }
+        unreachable!()
+    }
 
-        let mut right = mmap.into_view_sync();
-        let mut left_mid_len = 0;
-        for (i, m) in metrics.iter_mut().enumerate() {
-            let value_block_offset = value_section_offset + i * VALUE_BLOCK_LEN as usize;
+    // write locks on the slots of each metric's value blocks
+    fn lock_slots(slots: &[Vec<ValueSlot>]) -> Vec<Vec<RwLockWriteGuard<'_, Option<ValueView>>>> {
+        slots.iter().map(|slots| slots.iter().map(ValueSlot::lock).collect()).collect()
+    }
 
-            let (left, r) = right.split_at(value_block_offset - left_mid_len).unwrap();
-            let (middle, r) = r.split_at(8).unwrap();
-            right = r;
-            left_mid_len = left.len() + middle.len();
+    fn point_slots(guards: &mut [Vec<RwLockWriteGuard<'_, Option<ValueView>>>], views: Vec<Vec<ValueView>>) {
+        for (guards, views) in guards.iter_mut().zip(views) {
+            for (guard, view) in guards.iter_mut().zip(views) {
+                **guard = Some(view);
+            }
+        }
+    }
 
-            m.mmap_view = Some(middle);
+    fn split_mmap_views(mapping: &Arc<Mapping>, layout: &Layout, metrics: &[&mut dyn MMVMetric]) -> Vec<Vec<ValueView>> {
+        let mut value_block_offset = layout.value_section_offset;
+        let mut metric_views = Vec::new();
+        for m in metrics.iter() {
+            let mut views = Vec::new();
+            for _ in 0..m.desc().n_values() {
+                let mut view = ValueView {
+                    mapping: mapping.clone(),
+                    offset: value_block_offset,
+                    strings_offset: None
+                };
+                // string values start out pointing to the first of their
+                // pair of string blocks
+                if m.desc().mtype == MetricType::String {
+                    view.strings_offset = view.with_fields(
+                        |_, extra| extra.load(Ordering::Relaxed)).ok();
+                }
+                views.push(view);
+                value_block_offset += VALUE_BLOCK_LEN;
+            }
+            metric_views.push(views);
         }
+        metric_views
+    }
+}
+
+impl Drop for MMV {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests;
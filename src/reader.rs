@@ -0,0 +1,656 @@
+//! Reading back MMV files
+//!
+//! A `Reader` maps an existing MMV file read-only, checks its header and
+//! parses its instance domains, metrics and labels. Values are read from
+//! the mapping on each call, so they reflect updates made by the writer.
+
+use memmap::{Mmap, Protection};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::{
+    Elapsed, Error, MMVFlags, MMVVersion, MetricSem, MetricType, Units,
+    HDR_LEN, GEN1_OFFSET, GEN2_OFFSET, TOC_BLOCK_LEN, INDOM_BLOCK_LEN, INSTANCE_V1_BLOCK_LEN,
+    INSTANCE_V2_BLOCK_LEN, METRIC_V1_BLOCK_LEN, METRIC_V2_BLOCK_LEN,
+    VALUE_BLOCK_LEN, STRING_BLOCK_LEN, LABEL_BLOCK_LEN, METRIC_NAME_MAX_LEN,
+    INSTANCE_NAME_MAX_LEN, INDOM_TOC_TYPE, INSTANCE_TOC_TYPE, METRIC_TOC_TYPE,
+    VALUE_TOC_TYPE, STRING_TOC_TYPE, LABEL_TOC_TYPE, LABEL_INDOM, LABEL_CLUSTER,
+    LABEL_ITEM, LABEL_INSTANCES
+};
+
+// bits of a label's flags giving what it applies to
+const LABEL_TYPES: u32 = LABEL_CLUSTER | LABEL_INDOM | LABEL_ITEM | LABEL_INSTANCES;
+
+// how often and how far apart a string value being written is read again
+// before giving up on its writer, which may have died part way through
+const STRING_READ_ATTEMPTS: u32 = 100;
+const STRING_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Kind of section of an MMV file
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Section {
+    Indoms,
+    Instances,
+    Metrics,
+    Values,
+    Strings,
+    Labels
+}
+
+/// Entry of the table of contents of an MMV file
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TocEntry {
+    pub section: Section,
+    pub n_entries: u32,
+    pub offset: u64
+}
+
+/// Instance domain read from an MMV file
+#[derive(Clone, PartialEq, Debug)]
+pub struct Indom {
+    pub serial: u32,
+    pub instances: Vec<Instance>,
+    pub shorthelp: Option<String>,
+    pub longhelp: Option<String>
+}
+
+/// Instance of an instance domain read from an MMV file
+#[derive(Clone, PartialEq, Debug)]
+pub struct Instance {
+    pub id: i32,
+    pub name: String
+}
+
+/// Metric read from an MMV file
+#[derive(Clone, PartialEq, Debug)]
+pub struct Metric {
+    pub name: String,
+    pub item: u32,
+    pub mtype: MetricType,
+    pub sem: MetricSem,
+    pub units: Units,
+    /// Serial of the metric's instance domain, if it has one
+    pub indom: Option<u32>,
+    pub shorthelp: Option<String>,
+    pub longhelp: Option<String>
+}
+
+/// What a label read from an MMV file applies to
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LabelTarget {
+    /// Every metric in the file, with the file's cluster id
+    Cluster(u32),
+    /// Instance domain with the given serial
+    Indom(u32),
+    /// Metric with the given item id
+    Item(u32),
+    /// Instance with the given internal id of the instance domain with
+    /// the given serial
+    Instance(u32, i32)
+}
+
+/// Label read from an MMV file, with its JSON payload
+#[derive(Clone, PartialEq, Debug)]
+pub struct Label {
+    pub target: LabelTarget,
+    /// Bits of the label's flags other than those giving its target, such
+    /// as PCP's `PM_LABEL_OPTIONAL`
+    pub flags: u32,
+    pub payload: String
+}
+
+/// Current value of a metric or one of its instances
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    Float(f32),
+    Double(f64),
+    String(String),
+    Elapsed(Elapsed)
+}
+
+/// Value read from an MMV file along with the names of its metric and
+/// instance
+#[derive(Clone, PartialEq, Debug)]
+pub struct ValueEntry {
+    pub metric: String,
+    pub instance: Option<String>,
+    pub value: Value
+}
+
+// location of a value block, and the metric and instance it belongs to
+struct ValueRef {
+    offset: u64,
+    metric: usize,
+    instance: Option<String>
+}
+
+pub struct Reader {
+    mmap: Mmap,
+    version: MMVVersion,
+    flags: MMVFlags,
+    pid: i32,
+    cluster_id: u32,
+    generation: i64,
+    toc: Vec<TocEntry>,
+    indoms: Vec<Indom>,
+    metrics: Vec<Metric>,
+    labels: Vec<Label>,
+    strings: Vec<(u64, String)>,
+    values: Vec<ValueRef>,
+    lenient: bool,
+    warnings: Vec<String>
+}
+
+fn invalid<T>(what: &str) -> Result<T, Error> {
+    Err(Error::InvalidFile(what.to_owned()))
+}
+
+// bounds-checked little-endian accessors for the mapped file
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn slice(&self, offset: u64, len: u64) -> Result<&'a [u8], Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.0.len() as u64 =>
+                Ok(&self.0[offset as usize..end as usize]),
+            _ => invalid("block out of bounds")
+        }
+    }
+
+    fn u32_at(&self, offset: u64) -> Result<u32, Error> {
+        Ok(LittleEndian::read_u32(self.slice(offset, 4)?))
+    }
+
+    fn i32_at(&self, offset: u64) -> Result<i32, Error> {
+        Ok(LittleEndian::read_i32(self.slice(offset, 4)?))
+    }
+
+    fn u64_at(&self, offset: u64) -> Result<u64, Error> {
+        Ok(LittleEndian::read_u64(self.slice(offset, 8)?))
+    }
+
+    fn i64_at(&self, offset: u64) -> Result<i64, Error> {
+        Ok(LittleEndian::read_i64(self.slice(offset, 8)?))
+    }
+
+    // nul-terminated string of at most `max_len` bytes including the nul
+    fn str_at(&self, offset: u64, max_len: u64) -> Result<String, Error> {
+        let len = ::std::cmp::min(max_len, (self.0.len() as u64).saturating_sub(offset));
+        let bytes = self.slice(offset, len)?;
+        match bytes.iter().position(|&b| b == 0) {
+            Some(end) => Ok(String::from_utf8_lossy(&bytes[..end]).into_owned()),
+            None => invalid("unterminated string")
+        }
+    }
+
+    // string block at `offset`, or `None` for a zero offset
+    fn string_block(&self, offset: u64) -> Result<Option<String>, Error> {
+        match offset {
+            0 => Ok(None),
+            _ => self.str_at(offset, STRING_BLOCK_LEN).map(Some)
+        }
+    }
+}
+
+impl Reader {
+    /// Maps the MMV file at `path` and parses it, failing if it isn't a
+    /// complete MMV file of a known version
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
+        let mmap = Mmap::open_path(path, Protection::Read)?;
+        Reader::parse(mmap, false)
+    }
+
+    /// Maps the already open MMV `file` and parses it, like `open`
+    pub fn from_file(file: &File) -> Result<Reader, Error> {
+        let mmap = Mmap::open(file, Protection::Read)?;
+        Reader::parse(mmap, false)
+    }
+
+    /// Opens the MMV file at `path` like `open`, but parses it even if its
+    /// generation numbers differ, reporting that in `warnings` instead, and
+    /// reads its values even once it's no longer current. Meant for tools
+    /// inspecting files, which can't rely on the file being complete.
+    pub fn open_lenient<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
+        let mmap = Mmap::open_path(path, Protection::Read)?;
+        Reader::parse(mmap, true)
+    }
+
+    /// Opens the MMV file at `path` like `open`, but while the file is
+    /// being written retries after `interval`, up to `attempts` times in
+    /// total
+    pub fn open_retry<P: AsRef<Path>>(path: P, attempts: u32, interval: Duration) -> Result<Reader, Error> {
+        let mut attempt = 1;
+        loop {
+            match Reader::open(path.as_ref()) {
+                Err(Error::GenerationMismatch(..)) if attempt < attempts => {
+                    thread::sleep(interval);
+                    attempt += 1;
+                },
+                result => return result
+            }
+        }
+    }
+
+    fn parse(mmap: Mmap, lenient: bool) -> Result<Reader, Error> {
+        let mut reader = Reader {
+            mmap: mmap,
+            version: MMVVersion::V1,
+            flags: MMVFlags::empty(),
+            pid: 0,
+            cluster_id: 0,
+            generation: 0,
+            toc: Vec::new(),
+            indoms: Vec::new(),
+            metrics: Vec::new(),
+            labels: Vec::new(),
+            strings: Vec::new(),
+            values: Vec::new(),
+            lenient: lenient,
+            warnings: Vec::new()
+        };
+        // a writer changes generation1 first and generation2 last, so
+        // they're read in the opposite order
+        let gen2 = reader.generation_at(GEN2_OFFSET)?;
+        let parsed = reader.parse_blocks();
+        let gen1 = reader.generation_at(GEN1_OFFSET)?;
+        if gen1 != gen2 || gen1 == 0 {
+            if !lenient {
+                return Err(Error::GenerationMismatch(gen1, gen2));
+            }
+            reader.warnings.push(if gen1 == gen2 {
+                "MMV file generation is 0, i.e. it's still being written".to_owned()
+            } else {
+                Error::GenerationMismatch(gen1, gen2).to_string()
+            });
+        }
+        parsed?;
+        reader.generation = gen1;
+        Ok(reader)
+    }
+
+    fn parse_blocks(&mut self) -> Result<(), Error> {
+        let b = Bytes(unsafe { self.mmap.as_slice() });
+
+        if b.slice(0, 4)? != b"MMV\0" {
+            return invalid("bad magic");
+        }
+        let version = match MMVVersion::from_raw(b.u32_at(4)?) {
+            Some(version) => version,
+            None => return invalid("unknown version")
+        };
+        let n_toc = b.i32_at(24)?;
+        let flags = MMVFlags::from_bits_truncate(b.u32_at(28)?);
+        let pid = b.i32_at(32)?;
+        let cluster_id = b.u32_at(36)?;
+
+        let mut toc: Vec<TocEntry> = Vec::new();
+        for i in 0..n_toc.max(0) as u64 {
+            let toc_offset = HDR_LEN + i*TOC_BLOCK_LEN;
+            let (section, block_len) = match b.u32_at(toc_offset)? {
+                INDOM_TOC_TYPE => (Section::Indoms, INDOM_BLOCK_LEN),
+                INSTANCE_TOC_TYPE => (Section::Instances, match version {
+                    MMVVersion::V1 => INSTANCE_V1_BLOCK_LEN,
+                    _ => INSTANCE_V2_BLOCK_LEN
+                }),
+                METRIC_TOC_TYPE => (Section::Metrics, match version {
+                    MMVVersion::V1 => METRIC_V1_BLOCK_LEN,
+                    _ => METRIC_V2_BLOCK_LEN
+                }),
+                VALUE_TOC_TYPE => (Section::Values, VALUE_BLOCK_LEN),
+                STRING_TOC_TYPE => (Section::Strings, STRING_BLOCK_LEN),
+                LABEL_TOC_TYPE => (Section::Labels, LABEL_BLOCK_LEN),
+                _ => return invalid("unknown TOC section type")
+            };
+            let entry = TocEntry {
+                section: section,
+                n_entries: b.u32_at(toc_offset + 4)?,
+                offset: b.u64_at(toc_offset + 8)?
+            };
+            b.slice(entry.offset, entry.n_entries as u64*block_len)?;
+            // the fields of value blocks are accessed atomically by
+            // `SharedMMV`, which needs them 8-byte aligned
+            if entry.offset & 7 != 0 {
+                return invalid("misaligned section");
+            }
+            if toc.iter().any(|e| e.section == section) {
+                return invalid("duplicate TOC section");
+            }
+            toc.push(entry);
+        }
+        let entries = |section: Section| toc.iter()
+            .find(|e| e.section == section)
+            .map_or((0, 0), |e| (e.n_entries as u64, e.offset));
+
+        // instances by offset, for resolving references from indoms
+        // and values
+        let (n_instances, instance_section_offset) = entries(Section::Instances);
+        let mut instances = HashMap::new();
+        for i in 0..n_instances {
+            let offset = match version {
+                MMVVersion::V1 => instance_section_offset + i*INSTANCE_V1_BLOCK_LEN,
+                _ => instance_section_offset + i*INSTANCE_V2_BLOCK_LEN
+            };
+            let name = match version {
+                MMVVersion::V1 => b.str_at(offset + 16, INSTANCE_NAME_MAX_LEN)?,
+                _ => match b.string_block(b.u64_at(offset + 16)?)? {
+                    Some(name) => name,
+                    None => return invalid("instance without a name")
+                }
+            };
+            instances.insert(offset, Instance {
+                id: b.i32_at(offset + 12)?,
+                name: name
+            });
+        }
+
+        let (n_indoms, indom_section_offset) = entries(Section::Indoms);
+        let mut indoms = Vec::new();
+        for i in 0..n_indoms {
+            let offset = indom_section_offset + i*INDOM_BLOCK_LEN;
+            let count = b.u32_at(offset + 4)? as u64;
+            let first_instance = b.u64_at(offset + 8)?;
+            let instance_block_len = match version {
+                MMVVersion::V1 => INSTANCE_V1_BLOCK_LEN,
+                _ => INSTANCE_V2_BLOCK_LEN
+            };
+            let mut indom_instances = Vec::new();
+            for j in 0..count {
+                match instances.get(&(first_instance + j*instance_block_len)) {
+                    Some(inst) => indom_instances.push(inst.clone()),
+                    None => return invalid("dangling instance offset")
+                }
+            }
+            indoms.push(Indom {
+                serial: b.u32_at(offset)?,
+                instances: indom_instances,
+                shorthelp: b.string_block(b.u64_at(offset + 16)?)?,
+                longhelp: b.string_block(b.u64_at(offset + 24)?)?
+            });
+        }
+
+        let (n_metrics, metric_section_offset) = entries(Section::Metrics);
+        let mut metrics = Vec::new();
+        let mut metric_indices = HashMap::new();
+        for i in 0..n_metrics {
+            let (offset, name, fields_offset) = match version {
+                MMVVersion::V1 => {
+                    let offset = metric_section_offset + i*METRIC_V1_BLOCK_LEN;
+                    (offset, b.str_at(offset, METRIC_NAME_MAX_LEN)?, offset + METRIC_NAME_MAX_LEN)
+                },
+                _ => {
+                    let offset = metric_section_offset + i*METRIC_V2_BLOCK_LEN;
+                    let name = match b.string_block(b.u64_at(offset)?)? {
+                        Some(name) => name,
+                        None => return invalid("metric without a name")
+                    };
+                    (offset, name, offset + 8)
+                }
+            };
+            let mtype = match MetricType::from_raw(b.u32_at(fields_offset + 4)?) {
+                Some(mtype) => mtype,
+                None => return invalid("unknown metric type")
+            };
+            let sem = match MetricSem::from_raw(b.u32_at(fields_offset + 8)?) {
+                Some(sem) => sem,
+                None => return invalid("unknown metric semantics")
+            };
+            let units = match Units::from_raw(b.u32_at(fields_offset + 12)?) {
+                Some(units) => units,
+                None => return invalid("bad metric units")
+            };
+            let indom = match b.u32_at(fields_offset + 16)? {
+                0 | 0xffff_ffff => None,
+                serial => Some(serial)
+            };
+            metric_indices.insert(offset, metrics.len());
+            metrics.push(Metric {
+                name: name,
+                item: b.u32_at(fields_offset)?,
+                mtype: mtype,
+                sem: sem,
+                units: units,
+                indom: indom,
+                shorthelp: b.string_block(b.u64_at(fields_offset + 24)?)?,
+                longhelp: b.string_block(b.u64_at(fields_offset + 32)?)?
+            });
+        }
+
+        let (n_values, value_section_offset) = entries(Section::Values);
+        let mut values = Vec::new();
+        for i in 0..n_values {
+            let offset = value_section_offset + i*VALUE_BLOCK_LEN;
+            let metric = match metric_indices.get(&b.u64_at(offset + 16)?) {
+                Some(&metric) => metric,
+                None => return invalid("dangling metric offset")
+            };
+            let instance = match b.u64_at(offset + 24)? {
+                0 => None,
+                instance_offset => match instances.get(&instance_offset) {
+                    Some(inst) => Some(inst.name.clone()),
+                    None => return invalid("dangling instance offset")
+                }
+            };
+            values.push(ValueRef {
+                offset: offset,
+                metric: metric,
+                instance: instance
+            });
+        }
+
+        let (n_labels, label_section_offset) = entries(Section::Labels);
+        let mut labels = Vec::new();
+        for i in 0..n_labels {
+            let offset = label_section_offset + i*LABEL_BLOCK_LEN;
+            let identity = b.u32_at(offset + 4)?;
+            let flags = b.u32_at(offset)?;
+            let target = match flags & LABEL_TYPES {
+                LABEL_CLUSTER => LabelTarget::Cluster(identity),
+                LABEL_INDOM => LabelTarget::Indom(identity),
+                LABEL_ITEM => LabelTarget::Item(identity),
+                LABEL_INSTANCES => LabelTarget::Instance(identity, b.i32_at(offset + 8)?),
+                _ => return invalid("unknown label type")
+            };
+            labels.push(Label {
+                target: target,
+                flags: flags & !LABEL_TYPES,
+                payload: b.str_at(offset + 12, LABEL_BLOCK_LEN - 12)?
+            });
+        }
+
+        let (n_strings, string_section_offset) = entries(Section::Strings);
+        let mut strings = Vec::new();
+        for i in 0..n_strings {
+            let offset = string_section_offset + i*STRING_BLOCK_LEN;
+            strings.push((offset, b.str_at(offset, STRING_BLOCK_LEN)?));
+        }
+
+        self.version = version;
+        self.flags = flags;
+        self.pid = pid;
+        self.cluster_id = cluster_id;
+        self.toc = toc;
+        self.indoms = indoms;
+        self.metrics = metrics;
+        self.labels = labels;
+        self.strings = strings;
+        self.values = values;
+        Ok(())
+    }
+
+    // generation number at `offset` in the header
+    fn generation_at(&self, offset: u64) -> Result<i64, Error> {
+        Ok(self.ordered_u64_at(offset)? as i64)
+    }
+
+    // u64 at `offset`, read in order with the reads of the rest of the
+    // file around it
+    fn ordered_u64_at(&self, offset: u64) -> Result<u64, Error> {
+        let b = Bytes(unsafe { self.mmap.as_slice() });
+        let bytes = b.slice(offset, 8)?;
+        fence(Ordering::Acquire);
+        let x = unsafe { ptr::read_volatile(bytes.as_ptr() as *const u64) };
+        fence(Ordering::Acquire);
+        Ok(u64::from_le(x))
+    }
+
+    pub fn version(&self) -> MMVVersion {
+        self.version
+    }
+
+    pub fn flags(&self) -> MMVFlags {
+        self.flags
+    }
+
+    /// Process id of the writer
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    pub fn cluster_id(&self) -> u32 {
+        self.cluster_id
+    }
+
+    /// Generation number the file was written with
+    pub fn generation(&self) -> i64 {
+        self.generation
+    }
+
+    /// Whether the file is unchanged since it was opened. A writer
+    /// re-publishing its metrics marks its previous file as stale, after
+    /// which it should be opened again.
+    pub fn is_current(&self) -> Result<bool, Error> {
+        let gen2 = self.generation_at(GEN2_OFFSET)?;
+        let gen1 = self.generation_at(GEN1_OFFSET)?;
+        Ok(gen1 == self.generation && gen2 == self.generation)
+    }
+
+    fn check_current(&self) -> Result<(), Error> {
+        if self.lenient {
+            return Ok(());
+        }
+        let gen1 = self.generation_at(GEN1_OFFSET)?;
+        let gen2 = self.generation_at(GEN2_OFFSET)?;
+        if gen1 != self.generation || gen2 != self.generation {
+            return Err(Error::GenerationMismatch(gen1, gen2));
+        }
+        Ok(())
+    }
+
+    /// Problems found while parsing a file opened with `open_lenient`
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn toc(&self) -> &[TocEntry] {
+        &self.toc
+    }
+
+    pub fn indoms(&self) -> &[Indom] {
+        &self.indoms
+    }
+
+    pub fn indom(&self, serial: u32) -> Option<&Indom> {
+        self.indoms.iter().find(|indom| indom.serial == serial)
+    }
+
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+
+    pub fn metric(&self, name: &str) -> Option<&Metric> {
+        self.metrics.iter().find(|m| m.name == name)
+    }
+
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    /// Contents of each block of the string section, with its offset
+    pub fn strings(&self) -> &[(u64, String)] {
+        &self.strings
+    }
+
+    /// Current values of every metric and instance in the file, failing
+    /// with `Error::GenerationMismatch` if the file is no longer current,
+    /// unless it was opened with `open_lenient`
+    pub fn values(&self) -> Result<Vec<ValueEntry>, Error> {
+        let values = self.values.iter()
+            .map(|v| Ok(ValueEntry {
+                metric: self.metrics[v.metric].name.clone(),
+                instance: v.instance.clone(),
+                value: self.read_value(v)?
+            }))
+            .collect();
+        self.check_current()?;
+        values
+    }
+
+    /// Current value of `metric`, or of its instance named `instance` if
+    /// it has an instance domain
+    pub fn value(&self, metric: &str, instance: Option<&str>) -> Result<Option<Value>, Error> {
+        let value = match self.find_value(metric, instance) {
+            Some(v) => self.read_value(v).map(Some),
+            None => Ok(None)
+        };
+        self.check_current()?;
+        value
+    }
+
+    /// Offset in the file of the value block of `metric`, or of its
+    /// instance named `instance` if it has an instance domain
+    pub fn value_offset(&self, metric: &str, instance: Option<&str>) -> Option<u64> {
+        self.find_value(metric, instance).map(|v| v.offset)
+    }
+
+    fn find_value(&self, metric: &str, instance: Option<&str>) -> Option<&ValueRef> {
+        self.values.iter().find(|v|
+            self.metrics[v.metric].name == metric && v.instance.as_ref().map(|s| &s[..]) == instance)
+    }
+
+    // The value field of a string's value block counts the writes to the
+    // string, and is odd while one is in progress, so the string is read
+    // again until the count is even and unchanged across reading it.
+    fn read_str(&self, offset: u64) -> Result<String, Error> {
+        let b = Bytes(unsafe { self.mmap.as_slice() });
+        for _ in 0..STRING_READ_ATTEMPTS {
+            let count = self.ordered_u64_at(offset)?;
+            if count & 1 == 0 {
+                let s = b.u64_at(offset + 8).and_then(|extra| b.str_at(extra, STRING_BLOCK_LEN));
+                if self.ordered_u64_at(offset)? == count {
+                    return s;
+                }
+            }
+            thread::sleep(STRING_RETRY_INTERVAL);
+        }
+        invalid("string value left partly written")
+    }
+
+    fn read_value(&self, v: &ValueRef) -> Result<Value, Error> {
+        let b = Bytes(unsafe { self.mmap.as_slice() });
+        let raw = b.u64_at(v.offset)?;
+        let extra = b.i64_at(v.offset + 8)?;
+        Ok(match self.metrics[v.metric].mtype {
+            MetricType::I32 => Value::I32(raw as u32 as i32),
+            MetricType::U32 => Value::U32(raw as u32),
+            MetricType::I64 => Value::I64(raw as i64),
+            MetricType::U64 => Value::U64(raw),
+            MetricType::Float => Value::Float(f32::from_bits(raw as u32)),
+            MetricType::Double => Value::Double(f64::from_bits(raw)),
+            MetricType::String => Value::String(self.read_str(v.offset)?),
+            MetricType::Elapsed => Value::Elapsed(Elapsed {
+                total: raw as i64,
+                start: extra.wrapping_neg()
+            })
+        })
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{Error, Metric, MMV, MMVMetric};
+
+type SharedMetric = Arc<Mutex<dyn MMVMetric + Send>>;
+
+fn lock<M: ?Sized>(metric: &Mutex<M>) -> MutexGuard<'_, M> {
+    // a panic while updating a metric leaves nothing half-done that
+    // re-mapping could trip over
+    metric.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Set of metrics that can grow and shrink after being mapped
+///
+/// Each change re-publishes the MMV file with a new layout and generation
+/// number. Metrics keep their current values across changes, and the
+/// handles returned by `register`, along with the atomic handles taken
+/// from them, are re-pointed to the new file.
+///
+/// Handles must not be locked while calling `register` or `unregister`,
+/// as those lock every registered metric to re-point it.
+pub struct Registry {
+    mmv: MMV,
+    metrics: Vec<SharedMetric>
+}
+
+impl Registry {
+    /// Creates an empty registry, which writes the file of `mmv` once a
+    /// metric is registered
+    pub fn new(mmv: MMV) -> Self {
+        Registry {
+            mmv: mmv,
+            metrics: Vec::new()
+        }
+    }
+
+    /// Adds `metric` to the MMV file, returning a handle for updating it
+    pub fn register<T>(&mut self, metric: Metric<T>) -> Result<Arc<Mutex<Metric<T>>>, Error>
+        where Metric<T>: MMVMetric + Send + 'static {
+
+        let handle = Arc::new(Mutex::new(metric));
+        self.metrics.push(handle.clone());
+        if let Err(err) = self.publish() {
+            self.metrics.pop();
+            return Err(err);
+        }
+        Ok(handle)
+    }
+
+    /// Removes the metric named `name` from the MMV file. Its handle is
+    /// left unmapped, so updating it fails with `Error::NotMapped`.
+    pub fn unregister(&mut self, name: &str) -> Result<(), Error> {
+        let index = match self.metrics.iter().position(|m| lock(m).desc().name == name) {
+            Some(index) => index,
+            None => return Err(Error::UnknownMetric(name.to_owned()))
+        };
+        let metric = self.metrics.remove(index);
+        if let Err(err) = self.publish() {
+            self.metrics.insert(index, metric);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Names of the registered metrics
+    pub fn names(&self) -> Vec<String> {
+        self.metrics.iter().map(|m| lock(m).desc().name.clone()).collect()
+    }
+
+    /// Re-creates the MMV file at `path` in a child process forked after
+    /// metrics were registered, as with `MMV::remap_after_fork`
+    pub fn remap_after_fork(&mut self, path: &str) -> Result<(), Error> {
+        self.with_locked(|mmv, metrics| mmv.remap_after_fork(path, metrics))
+    }
+
+    /// Closes the MMV file as with `MMV::close`, after which updating
+    /// the registered metrics fails with `Error::NotMapped`
+    pub fn close(self) -> Result<(), Error> {
+        self.mmv.close()
+    }
+
+    fn publish(&mut self) -> Result<(), Error> {
+        self.with_locked(|mmv, metrics| mmv.map(metrics))
+    }
+
+    // re-maps every metric with `f`, holding their locks so that they
+    // aren't updated while being re-pointed. Their atomic handles are held
+    // off by `MMV::map` itself.
+    fn with_locked<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut MMV, &mut [&mut dyn MMVMetric]) -> Result<(), Error> {
+
+        let mut guards: Vec<_> = self.metrics.iter().map(|m| lock(m)).collect();
+        let mut metrics: Vec<&mut dyn MMVMetric> = guards.iter_mut()
+            .map(|guard| &mut **guard as &mut dyn MMVMetric)
+            .collect();
+        f(&mut self.mmv, &mut metrics)
+    }
+}
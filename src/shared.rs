@@ -0,0 +1,98 @@
+use memmap::{Mmap, Protection};
+use std::fs::OpenOptions;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{AtomicHandle, Error, Mapping, MetricValue, Reader, ValueSlot, ValueView};
+
+/// MMV file written by another process, attached to for updating the
+/// values of its numeric metrics
+///
+/// This lets the processes of a pre-fork server, say, share counters in
+/// a single file. One process writes the file with `MMV::map`, and the
+/// others attach to it by path and take atomic handles to its metrics by
+/// name. The layout of the file is left as is.
+///
+/// Attaching maps the file itself rather than its path, so if the writer
+/// re-publishes or removes it, updates go to the old file, which
+/// `is_current` then reports. Dropping the `SharedMMV` unmaps the file,
+/// after which updating the handles taken from it fails with
+/// `Error::NotMapped`.
+pub struct SharedMMV {
+    reader: Reader,
+    mapping: Arc<Mapping>
+}
+
+impl SharedMMV {
+    /// Attaches to the MMV file at `path`, failing if it isn't a complete
+    /// MMV file
+    pub fn attach<P: AsRef<Path>>(path: P) -> Result<SharedMMV, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let reader = Reader::from_file(&file)?;
+        let mmap = Mmap::open(&file, Protection::ReadWrite)?;
+        Ok(SharedMMV {
+            reader: reader,
+            mapping: Arc::new(Mapping::new(mmap.into_view_sync()))
+        })
+    }
+
+    /// Reader of the attached file, for listing its metrics and instances
+    pub fn reader(&self) -> &Reader {
+        &self.reader
+    }
+
+    /// Whether the writer hasn't re-published the file since it was
+    /// attached to
+    pub fn is_current(&self) -> Result<bool, Error> {
+        self.reader.is_current()
+    }
+
+    /// Returns a handle for updating the value of `metric`, or of its
+    /// instance named `instance` if it has an instance domain.
+    ///
+    /// Fails if `T` isn't the metric's type, and updates fail for
+    /// counters as with the writer's handles.
+    pub fn atomic_handle<T: MetricValue>(
+        &self, metric: &str, instance: Option<&str>) -> Result<AtomicHandle<T>, Error> {
+
+        let m = match self.reader.metric(metric) {
+            Some(m) => m,
+            None => return Err(Error::UnknownMetric(metric.to_owned()))
+        };
+        if m.mtype != T::metric_type() {
+            return Err(Error::WrongMetricType(m.name.clone(), m.mtype));
+        }
+        let offset = match (m.indom, instance) {
+            (Some(_), None) => return Err(Error::HasIndom),
+            (_, Some(instance)) => self.reader.value_offset(metric, Some(instance))
+                .ok_or_else(|| Error::UnknownInstance(instance.to_owned()))?,
+            (None, None) => self.reader.value_offset(metric, None)
+                .ok_or_else(|| Error::UnknownMetric(metric.to_owned()))?
+        };
+        Ok(AtomicHandle {
+            slot: ValueSlot::new(Some(ValueView {
+                mapping: self.mapping.clone(),
+                offset: offset,
+                strings_offset: None
+            })),
+            sem: m.sem,
+            _val: PhantomData
+        })
+    }
+
+    /// Flushes and unmaps the file, after which updating the handles
+    /// taken from it fails with `Error::NotMapped`.
+    ///
+    /// Dropping the `SharedMMV` does the same, but ignores any error.
+    pub fn close(self) -> Result<(), Error> {
+        self.mapping.close()?;
+        Ok(())
+    }
+}
+
+impl Drop for SharedMMV {
+    fn drop(&mut self) {
+        let _ = self.mapping.close();
+    }
+}
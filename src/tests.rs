@@ -0,0 +1,627 @@
+// Tests of the MMV files written by `MMV::map`, read back from disk
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::env;
+use std::fs;
+use std::process;
+
+use reader::{LabelTarget, Value};
+use super::*;
+
+// path in the temporary directory unique to the test, as tests run in
+// parallel in the same process
+fn test_path(name: &str) -> String {
+    let path = env::temp_dir().join(format!("mmv-test-{}-{}", process::id(), name));
+    let _ = fs::remove_file(&path);
+    path.to_string_lossy().into_owned()
+}
+
+fn u32_at(file: &[u8], offset: u64) -> u32 {
+    LittleEndian::read_u32(&file[offset as usize..])
+}
+
+fn i32_at(file: &[u8], offset: u64) -> i32 {
+    LittleEndian::read_i32(&file[offset as usize..])
+}
+
+fn u64_at(file: &[u8], offset: u64) -> u64 {
+    LittleEndian::read_u64(&file[offset as usize..])
+}
+
+// string up to its nul
+fn str_at(file: &[u8], offset: u64) -> &str {
+    let s = &file[offset as usize..];
+    std::str::from_utf8(&s[..s.iter().position(|&b| b == 0).unwrap()]).unwrap()
+}
+
+// number of entries in and offset of the section of type `toc_type`
+fn section(file: &[u8], toc_type: u32) -> (u64, u64) {
+    (0..i32_at(file, 24) as u64)
+        .map(|i| HDR_LEN + i*TOC_BLOCK_LEN)
+        .find(|&toc| u32_at(file, toc) == toc_type)
+        .map(|toc| (u32_at(file, toc + 4) as u64, u64_at(file, toc + 8)))
+        .unwrap()
+}
+
+#[test]
+fn labels() -> Result<(), Error> {
+    let path = test_path("labels");
+    let mut disks = Indom::new(7, &[(0, "sda"), (1, "sdb")], "", "")?;
+    disks.add_label("bus", "sata")?;
+    disks.add_instance_label("sdb", "slot", 2i64)?;
+    let mut reads = Metric::<i64>::counter("disk.reads").item(1).indom(&disks).build()?;
+    reads.add_label("unit", "ops")?;
+    let mut mmv = MMV::new(&path, PROCESS, 9)?;
+    mmv.add_label("app", "test")?;
+    mmv.map(&mut [&mut reads])?;
+
+    let file = fs::read(&path).unwrap();
+    assert_eq!(u32_at(&file, 4), 3);
+    let (n_labels, label_section) = section(&file, LABEL_TOC_TYPE);
+    let labels: Vec<_> = (0..n_labels)
+        .map(|i| label_section + i*LABEL_BLOCK_LEN)
+        .map(|l| (u32_at(&file, l), u32_at(&file, l + 4), i32_at(&file, l + 8), str_at(&file, l + 12)))
+        .collect();
+    assert_eq!(labels, [
+        (LABEL_CLUSTER, 9, IN_NULL, "{\"app\":\"test\"}"),
+        (LABEL_INDOM, 7, IN_NULL, "{\"bus\":\"sata\"}"),
+        (LABEL_INSTANCES, 7, 1, "{\"slot\":2}"),
+        (LABEL_ITEM, 1, IN_NULL, "{\"unit\":\"ops\"}")
+    ]);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn labels_need_v3() -> Result<(), Error> {
+    let path = test_path("labels-v3");
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0i64, "", "")?;
+    c.add_label("x", true)?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.set_version(MMVVersion::V1);
+    mmv.map(&mut [&mut c])?;
+    assert_eq!(u32_at(&fs::read(&path).unwrap(), 4), 3);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn indom_labels_added_later() -> Result<(), Error> {
+    let path = test_path("indom-labels-later");
+    let mut disks = Indom::new(7, &[(0, "sda"), (1, "sdb")], "", "")?;
+    let mut reads = Metric::<i64>::counter("disk.reads").item(1).indom(&disks).build()?;
+    disks.add_label("bus", "sata")?;
+    let mut writes = Metric::<i64>::counter("disk.writes").item(2).indom(&disks).build()?;
+    disks.add_instance_label("sda", "slot", 1i64)?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.map(&mut [&mut reads, &mut writes])?;
+
+    let labels = Reader::open(&path)?.labels().iter()
+        .map(|l| (l.target, l.payload.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(labels, [
+        (LabelTarget::Indom(7), "{\"bus\":\"sata\"}".to_owned()),
+        (LabelTarget::Instance(7, 0), "{\"slot\":1}".to_owned())
+    ]);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+// value and extra fields of the `i`th value block
+fn value_fields(path: &str, i: u64) -> (u64, u64) {
+    let file = fs::read(path).unwrap();
+    let (_, value_section) = section(&file, VALUE_TOC_TYPE);
+    let block = value_section + i*VALUE_BLOCK_LEN;
+    (u64_at(&file, block), u64_at(&file, block + 8))
+}
+
+// string the `i`th value block points to
+fn value_str(path: &str, i: u64) -> String {
+    str_at(&fs::read(path).unwrap(), value_fields(path, i).1).to_owned()
+}
+
+#[test]
+fn strings() -> Result<(), Error> {
+    let path = test_path("strings");
+    let disks = Indom::new(7, &[(0, "sda"), (1, "sdb")], "", "")?;
+    let mut s = Metric::new_string("version", 1, MetricSem::Discrete, "1.0", "", "")?;
+    let mut names = Metric::new_string_with_indom("names", 2, MetricSem::Discrete, &disks, "", "", "")?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.map(&mut [&mut s, &mut names])?;
+    assert_eq!(value_str(&path, 0), "1.0");
+
+    // each update goes to the other string block of the pair
+    let (_, first_block) = value_fields(&path, 0);
+    for val in &["2.0", "3.0-beta", ""] {
+        s.set_val(val)?;
+        assert_eq!(value_str(&path, 0), *val);
+    }
+    // with the value field counting the updates
+    assert_eq!(value_fields(&path, 0), (6, first_block + STRING_BLOCK_LEN));
+
+    names.instance_mut("sdb").unwrap().set_val("backup")?;
+    assert_eq!((value_str(&path, 1), value_str(&path, 2)), ("".to_owned(), "backup".to_owned()));
+    assert!(matches!(s.set_val(&"x".repeat(256)), Err(Error::StringTooLong(256))));
+    assert_eq!(s.val(), "");
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn elapsed() -> Result<(), Error> {
+    let path = test_path("elapsed");
+    let disks = Indom::new(7, &[(0, "sda"), (1, "sdb")], "", "")?;
+    let mut busy = Metric::new_elapsed("busy", 1, "", "")?;
+    let mut io = Metric::new_elapsed_with_indom("io", 2, &disks, "", "")?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.map(&mut [&mut busy, &mut io])?;
+
+    // a running interval is recorded as its negated start time
+    busy.start()?;
+    let start = busy.val().start;
+    assert!(busy.val().is_running());
+    assert_eq!(value_fields(&path, 0), (0, start.wrapping_neg() as u64));
+    busy.stop()?;
+    assert!(!busy.val().is_running());
+    assert_eq!(value_fields(&path, 0), (busy.val().total as u64, 0));
+
+    io.instance_mut("sdb").unwrap().start()?;
+    assert_eq!(value_fields(&path, 1), (0, 0));
+    assert_ne!(value_fields(&path, 2).1, 0);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn elapsed_builder_settings() -> Result<(), Error> {
+    let busy = Metric::elapsed("busy").item(1).help("Busy", "").build()?;
+    assert_eq!(busy.val(), Elapsed::default());
+    let settings_rejected = |b: MetricBuilder<Elapsed>|
+        matches!(b.build(), Err(Error::ElapsedSettings(ref name)) if name == "busy");
+    assert!(settings_rejected(Metric::elapsed("busy").units(Units::time(TimeScale::Sec))));
+    assert!(settings_rejected(Metric::<Elapsed>::instant("busy")
+        .units(Units::time(TimeScale::Microsec))));
+    assert!(settings_rejected(Metric::<Elapsed>::counter("busy")));
+    let running = Elapsed { total: 0, start: 1 };
+    assert!(settings_rejected(Metric::elapsed("busy").init_val(running)));
+    Ok(())
+}
+
+#[test]
+fn temp_file_removed_on_failure() -> Result<(), Error> {
+    // a directory can't be renamed over
+    let path = test_path("dir");
+    fs::create_dir(&path)?;
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0i64, "", "")?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    assert!(matches!(mmv.map(&mut [&mut c]), Err(Error::Io(_))));
+    assert!(fs::metadata(mmv.tmp_path()).is_err());
+    assert!(matches!(c.set_val(1), Err(Error::NotMapped)));
+    fs::remove_dir(&path)?;
+    Ok(())
+}
+
+// the only test setting PCP_TMP_DIR and PCP_CONF, as the environment is
+// shared by the tests running in parallel
+#[test]
+fn for_client() -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp_dir = env::temp_dir().join(format!("mmv-test-{}-pcp-tmp", process::id()));
+    let _ = fs::remove_dir_all(&tmp_dir);
+    env::set_var("PCP_TMP_DIR", &tmp_dir);
+    let mmv = MMV::for_client("app", MMVFlags::empty(), 0)?;
+    assert_eq!(mmv.path, tmp_dir.join("mmv/app").to_string_lossy());
+    let mode = fs::metadata(tmp_dir.join("mmv"))?.permissions().mode();
+    assert_eq!(mode & 0o7777, 0o1777);
+
+    // without PCP_TMP_DIR, its setting in $PCP_CONF is used
+    let conf_dir = env::temp_dir().join(format!("mmv-test-{}-pcp-conf", process::id()));
+    let _ = fs::remove_dir_all(&conf_dir);
+    fs::create_dir(&conf_dir)?;
+    let conf = conf_dir.join("pcp.conf");
+    fs::write(&conf, format!("# comment\nPCP_VAR_DIR=/var/lib/pcp\nPCP_TMP_DIR=\"{}\"\n",
+        conf_dir.join("tmp").display()))?;
+    env::remove_var("PCP_TMP_DIR");
+    env::set_var("PCP_CONF", &conf);
+    let mmv = MMV::for_client("app", MMVFlags::empty(), 0)?;
+    assert_eq!(mmv.path, conf_dir.join("tmp/mmv/app").to_string_lossy());
+    env::remove_var("PCP_CONF");
+
+    for client in &["", ".", "..", "a/b", "a\0b"] {
+        assert!(matches!(MMV::for_client(client, MMVFlags::empty(), 0),
+            Err(Error::InvalidClientName(_))));
+    }
+    fs::remove_dir_all(&tmp_dir)?;
+    fs::remove_dir_all(&conf_dir)?;
+    Ok(())
+}
+
+fn round_trip(name: &str, version: MMVVersion, labels: bool) -> Result<Reader, Error> {
+    let path = test_path(name);
+    let disks = Indom::new(7, &[(0, "sda"), (1, "sdb")], "Disks", "All the disks")?;
+    let mut reads = Metric::new(
+        "disk.reads", 1, MetricSem::Counter, Units::count(), 0u64, "Reads", "Disk reads")?;
+    let mut temp = Metric::<i32>::instant("disk.temp").item(2).indom(&disks).build()?;
+    let mut load = Metric::new("load", 3, MetricSem::Instant, Units::none(), 0.5f64, "", "")?;
+    let mut mmv = MMV::new(&path, PROCESS, 9)?;
+    mmv.set_version(version);
+    if labels {
+        reads.add_label("device", "all")?;
+        mmv.add_label("app", "test")?;
+    }
+    mmv.map(&mut [&mut reads, &mut temp, &mut load])?;
+    reads.set_val(3)?;
+    temp.instance_mut("sdb").unwrap().set_val(-40)?;
+    load.set_val(1.25)?;
+
+    let reader = Reader::open(&path)?;
+    assert_eq!(reader.version(), version);
+    assert_eq!(reader.flags(), PROCESS);
+    assert_eq!(reader.cluster_id(), 9);
+    assert_eq!(reader.pid(), process::id() as i32);
+
+    let m = reader.metric("disk.reads").unwrap();
+    assert_eq!((m.item, m.mtype, m.sem, m.indom), (1, MetricType::U64, MetricSem::Counter, None));
+    assert_eq!(m.units, Units::count());
+    assert_eq!((m.shorthelp.as_ref().map(|s| &s[..]), m.longhelp.as_ref().map(|s| &s[..])),
+        (Some("Reads"), Some("Disk reads")));
+    assert_eq!(reader.metric("disk.temp").unwrap().indom, Some(7));
+    let instances: Vec<_> = reader.indom(7).unwrap().instances.iter()
+        .map(|inst| (inst.id, &inst.name[..]))
+        .collect();
+    assert_eq!(instances, [(0, "sda"), (1, "sdb")]);
+
+    assert_eq!(reader.value("disk.reads", None)?, Some(Value::U64(3)));
+    assert_eq!(reader.value("disk.temp", Some("sda"))?, Some(Value::I32(0)));
+    assert_eq!(reader.value("disk.temp", Some("sdb"))?, Some(Value::I32(-40)));
+    assert_eq!(reader.value("load", None)?, Some(Value::Double(1.25)));
+    fs::remove_file(&path)?;
+    Ok(reader)
+}
+
+#[test]
+fn round_trip_v1() -> Result<(), Error> {
+    let reader = round_trip("v1", MMVVersion::V1, false)?;
+    assert!(reader.labels().is_empty());
+    Ok(())
+}
+
+#[test]
+fn round_trip_v2() -> Result<(), Error> {
+    round_trip("v2", MMVVersion::V2, false)?;
+    Ok(())
+}
+
+#[test]
+fn round_trip_v3_labels() -> Result<(), Error> {
+    let reader = round_trip("v3", MMVVersion::V3, true)?;
+    let labels: Vec<_> = reader.labels().iter().map(|l| (l.target, &l.payload[..])).collect();
+    assert_eq!(labels, [
+        (LabelTarget::Cluster(9), "{\"app\":\"test\"}"),
+        (LabelTarget::Item(1), "{\"device\":\"all\"}")
+    ]);
+    Ok(())
+}
+
+#[test]
+fn label_flags() -> Result<(), Error> {
+    let path = test_path("label-flags");
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0u32, "", "")?;
+    c.add_label("x", 1i64)?;
+    let mut mmv = MMV::new(&path, PROCESS, 9)?;
+    mmv.add_label("app", "test")?;
+    mmv.map(&mut [&mut c])?;
+    // PCP's PM_LABEL_OPTIONAL on the cluster label
+    let mut file = fs::read(&path)?;
+    let (_, label_section) = section(&file, LABEL_TOC_TYPE);
+    LittleEndian::write_u32(&mut file[label_section as usize..], LABEL_CLUSTER | 1 << 7);
+    fs::write(&path, &file)?;
+
+    let reader = Reader::open(&path)?;
+    let labels: Vec<_> = reader.labels().iter().map(|l| (l.target, l.flags)).collect();
+    assert_eq!(labels, [(LabelTarget::Cluster(9), 1 << 7), (LabelTarget::Item(1), 0)]);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn lenient_reader() -> Result<(), Error> {
+    let path = test_path("lenient");
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0u32, "", "")?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.map(&mut [&mut c])?;
+    let reader = Reader::open_lenient(&path)?;
+    assert!(reader.warnings().is_empty());
+
+    // values are still read once the file is re-published
+    mmv.map(&mut [&mut c])?;
+    assert!(!reader.is_current()?);
+    assert_eq!(reader.value("c", None)?, Some(Value::U32(0)));
+
+    // as if the writer were partway through writing the file
+    let mut file = fs::read(&path)?;
+    let gen1 = u64_at(&file, 8);
+    LittleEndian::write_u64(&mut file[16..], gen1 + 1);
+    fs::write(&path, &file)?;
+    assert!(matches!(Reader::open(&path), Err(Error::GenerationMismatch(..))));
+    let reader = Reader::open_lenient(&path)?;
+    assert_eq!(reader.warnings().len(), 1);
+    assert_eq!(reader.values()?.len(), 1);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn registry_register_unregister() -> Result<(), Error> {
+    let path = test_path("registry");
+    let mut registry = Registry::new(MMV::new(&path, MMVFlags::empty(), 0)?);
+    let a = registry.register(Metric::new("a", 1, MetricSem::Counter, Units::none(), 0u32, "", "")?)?;
+    let handle = a.lock().unwrap().atomic_handle()?;
+    handle.inc_by(5)?;
+    let b = registry.register(Metric::new_string("b", 2, MetricSem::Discrete, "x", "", "")?)?;
+    assert_eq!(registry.names(), ["a", "b"]);
+
+    // values are kept across re-publishing, and the handle follows the
+    // metric to the new file
+    handle.inc()?;
+    let reader = Reader::open(&path)?;
+    assert_eq!(reader.value("a", None)?, Some(Value::U32(6)));
+    assert_eq!(reader.value("b", None)?, Some(Value::String("x".to_owned())));
+
+    registry.unregister("a")?;
+    assert_eq!(registry.names(), ["b"]);
+    assert!(!reader.is_current()?);
+    assert!(matches!(handle.inc(), Err(Error::NotMapped)));
+    assert!(matches!(a.lock().unwrap().set_val(7), Err(Error::NotMapped)));
+    assert_eq!(handle.val(), 6);
+    assert!(matches!(registry.unregister("a"), Err(Error::UnknownMetric(_))));
+
+    b.lock().unwrap().set_val("y")?;
+    let reader = Reader::open(&path)?;
+    assert!(reader.metric("a").is_none());
+    assert_eq!(reader.value("b", None)?, Some(Value::String("y".to_owned())));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn counter_dec_rejected() -> Result<(), Error> {
+    let path = test_path("counter-dec");
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 5u32, "", "")?;
+    let mut g = Metric::new("g", 2, MetricSem::Instant, Units::none(), 5i32, "", "")?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.map(&mut [&mut c, &mut g])?;
+
+    assert!(matches!(c.dec(), Err(Error::CounterDecrease)));
+    let handle = c.atomic_handle()?;
+    assert!(matches!(handle.dec_by(2), Err(Error::CounterDecrease)));
+    handle.inc()?;
+    g.dec_by(7)?;
+    assert_eq!((c.val(), g.val()), (6, -2));
+
+    let reader = Reader::open(&path)?;
+    assert_eq!(reader.value("c", None)?, Some(Value::U32(6)));
+    assert_eq!(reader.value("g", None)?, Some(Value::I32(-2)));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn auto_items() -> Result<(), Error> {
+    let path = test_path("auto-items");
+    let mut m_ya = Metric::<u32>::counter("m_ya").build()?;
+    MMV::new(&path, MMVFlags::empty(), 0)?.map(&mut [&mut m_ya])?;
+    assert_eq!(Reader::open(&path)?.metric("m_ya").unwrap().item, 559);
+
+    // "m_eu" hashes to the same item, which "m_ya" keeps when mapped over
+    // its file again, as after a restart
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    let mut m_ya = Metric::<u32>::counter("m_ya").build()?;
+    let mut m_eu = Metric::<u32>::counter("m_eu").build()?;
+    assert!(matches!(mmv.map(&mut [&mut m_ya, &mut m_eu]), Err(Error::DuplicateItem(559))));
+    let mut m_eu = Metric::<u32>::counter("m_eu").item(7).build()?;
+    mmv.map(&mut [&mut m_ya, &mut m_eu])?;
+    let reader = Reader::open(&path)?;
+    assert_eq!((reader.metric("m_ya").unwrap().item, reader.metric("m_eu").unwrap().item), (559, 7));
+
+    // the item in the file is kept rather than the one hashed to
+    let mut m_eu = Metric::<u32>::counter("m_eu").build()?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.map(&mut [&mut m_eu])?;
+    assert_eq!(Reader::open(&path)?.metric("m_eu").unwrap().item, 7);
+
+    let mut dup = Metric::<u32>::counter("m_eu").build()?;
+    assert!(matches!(mmv.map(&mut [&mut m_eu, &mut dup]), Err(Error::DuplicateName(_))));
+    let mut dup = Metric::<u32>::counter("dup").item(7).build()?;
+    assert!(matches!(mmv.map(&mut [&mut m_eu, &mut dup]), Err(Error::DuplicateItem(7))));
+    assert!(matches!(Metric::<u32>::counter("big").item(1024).build(), Err(Error::ItemOutOfRange(1024))));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn invalid_names_and_clusters() -> Result<(), Error> {
+    for name in &["", "1a", "a..b", ".a", "a.", "a.1b", "a-b", "a b", "métrique"] {
+        assert!(Metric::new(name, 1, MetricSem::Counter, Units::none(), 0u32, "", "").is_err(),
+            "{:?} accepted", name);
+    }
+    Metric::new("a_1.b2.C_", 1, MetricSem::Counter, Units::none(), 0u32, "", "")?;
+
+    let path = test_path("invalid-cluster");
+    assert!(matches!(MMV::new(&path, MMVFlags::empty(), 4096), Err(Error::ClusterIdOutOfRange(4096))));
+    assert!(MMV::new(&path, MMVFlags::empty(), 4095)?.warnings().is_empty());
+    assert!(MMV::new(&path, NOPREFIX, 1)?.warnings().is_empty());
+    assert_eq!(MMV::new(&path, NOPREFIX, 0)?.warnings().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn remap_after_fork() -> Result<(), Error> {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, getpid, ForkResult};
+
+    let path = test_path("fork");
+    let child_path = test_path("fork-child");
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0u64, "", "")?;
+    let mut mmv = MMV::new(&path, PROCESS, 0)?;
+    mmv.set_remove_on_close(true);
+    mmv.map(&mut [&mut c])?;
+    c.inc_by(5)?;
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            // a panic can't fail the test from the child, so it reports
+            // through its exit status instead
+            let ok = (|| -> Result<bool, Error> {
+                let inherited = mmv.is_inherited();
+                let refused = matches!(mmv.map(&mut [&mut c]), Err(Error::InheritedFile(_)));
+                mmv.remap_after_fork(&child_path, &mut [&mut c])?;
+                c.inc()?;
+                let reader = Reader::open(&child_path)?;
+                Ok(inherited && refused && !mmv.is_inherited() && reader.pid() == getpid() &&
+                    reader.value("c", None)? == Some(Value::U64(6)))
+            })();
+            let closed = mmv.close().is_ok();
+            process::exit(if closed && ok.unwrap_or(false) { 0 } else { 1 });
+        },
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+
+    // the child's file is its own, and removed when it closed it
+    assert!(fs::metadata(&child_path).is_err());
+    let reader = Reader::open(&path)?;
+    assert_eq!(reader.pid(), process::id() as i32);
+    assert!(reader.is_current()?);
+    assert_eq!(reader.value("c", None)?, Some(Value::U64(5)));
+    mmv.close()?;
+    assert!(fs::metadata(&path).is_err());
+    Ok(())
+}
+
+#[test]
+fn shared_mmv() -> Result<(), Error> {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    let path = test_path("shared");
+    let disks = Indom::new(7, &[(0, "sda"), (1, "sdb")], "", "")?;
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 5u64, "", "")?;
+    let mut temp = Metric::<i32>::instant("temp").item(2).indom(&disks).build()?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.map(&mut [&mut c, &mut temp])?;
+
+    // another process attaches to the file and updates its values
+    match fork().unwrap() {
+        ForkResult::Child => {
+            let updated = SharedMMV::attach(&path).and_then(|shared| {
+                shared.atomic_handle::<u64>("c", None)?.inc_by(3)?;
+                shared.atomic_handle::<i32>("temp", Some("sdb"))?.set_val(-40)?;
+                shared.close()
+            });
+            process::exit(if updated.is_ok() { 0 } else { 1 });
+        },
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+    assert_eq!(c.val(), 8);
+    assert_eq!(temp.instance("sdb").unwrap().val(), -40);
+
+    let shared = SharedMMV::attach(&path)?;
+    assert!(shared.is_current()?);
+    assert!(matches!(shared.atomic_handle::<u32>("c", None), Err(Error::WrongMetricType(_, MetricType::U64))));
+    assert!(matches!(shared.atomic_handle::<i32>("temp", None), Err(Error::HasIndom)));
+    assert!(matches!(shared.atomic_handle::<i32>("temp", Some("sdc")), Err(Error::UnknownInstance(_))));
+    assert!(matches!(shared.atomic_handle::<u64>("d", None), Err(Error::UnknownMetric(_))));
+    let handle = shared.atomic_handle::<u64>("c", None)?;
+    assert!(matches!(handle.dec(), Err(Error::CounterDecrease)));
+    shared.close()?;
+    assert!(matches!(handle.inc(), Err(Error::NotMapped)));
+    assert_eq!(c.val(), 8);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn resume() -> Result<(), Error> {
+    let path = test_path("resume");
+    let disks = Indom::new(7, &[(0, "sda"), (1, "sdb")], "", "")?;
+    {
+        let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0u64, "", "")?;
+        let mut s = Metric::new_string("s", 2, MetricSem::Discrete, "a", "", "")?;
+        let mut e = Metric::new_elapsed_with_indom("e", 3, &disks, "", "")?;
+        let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+        mmv.map(&mut [&mut c, &mut s, &mut e])?;
+        c.inc_by(42)?;
+        s.set_val("b")?;
+        e.instance_mut("sda").unwrap().start()?;
+        e.instance_mut("sda").unwrap().stop()?;
+        e.instance_mut("sdb").unwrap().start()?;
+    }
+    // as if the writer died while setting the string, whose count it had
+    // made odd
+    let mut file = fs::read(&path)?;
+    let (_, value_section) = section(&file, VALUE_TOC_TYPE);
+    LittleEndian::write_u64(&mut file[(value_section + VALUE_BLOCK_LEN) as usize..], 3);
+    fs::write(&path, &file)?;
+    assert!(matches!(Reader::open(&path)?.value("s", None), Err(Error::InvalidFile(_))));
+
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0u64, "", "")?;
+    let mut s = Metric::new_string("s", 2, MetricSem::Discrete, "", "", "")?;
+    let mut e = Metric::new_elapsed_with_indom("e", 3, &disks, "", "")?;
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    mmv.resume(&mut [&mut c, &mut s, &mut e])?;
+    assert_eq!((c.val(), s.val()), (42, "b"));
+    // intervals left running are stopped
+    assert!(!e.instance("sdb").unwrap().val().is_running());
+
+    c.inc()?;
+    s.set_val("c")?;
+    let reader = Reader::open(&path)?;
+    assert_eq!(reader.value("c", None)?, Some(Value::U64(43)));
+    assert_eq!(reader.value("s", None)?, Some(Value::String("c".to_owned())));
+    assert_eq!(value_fields(&path, 1).0, 6);
+    assert_eq!(reader.value("e", Some("sdb"))?, Some(Value::Elapsed(Elapsed::default())));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn resume_in_use() -> Result<(), Error> {
+    use nix::unistd::getppid;
+
+    let path = test_path("resume-in-use");
+    let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0u64, "", "")?;
+    MMV::new(&path, PROCESS, 0)?.map(&mut [&mut c])?;
+    // as if written by the live parent process
+    let mut file = fs::read(&path)?;
+    LittleEndian::write_i32(&mut file[32..], getppid());
+    fs::write(&path, &file)?;
+    let mut mmv = MMV::new(&path, PROCESS, 0)?;
+    assert!(matches!(mmv.resume(&mut [&mut c]), Err(Error::FileInUse(_, pid)) if pid == getppid()));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn resume_schema_mismatch() -> Result<(), Error> {
+    let path = test_path("resume-mismatch");
+    {
+        let mut c = Metric::new("c", 1, MetricSem::Counter, Units::none(), 0u64, "", "")?;
+        MMV::new(&path, MMVFlags::empty(), 0)?.map(&mut [&mut c])?;
+    }
+    let mut mmv = MMV::new(&path, MMVFlags::empty(), 0)?;
+    let mut c = Metric::new("c", 1, MetricSem::Instant, Units::none(), 0u64, "", "")?;
+    match mmv.resume(&mut [&mut c]) {
+        Err(Error::SchemaMismatch(what)) => assert!(what.contains("semantics of \"c\"")),
+        result => panic!("{:?}", result)
+    }
+    let mut d = Metric::new("d", 1, MetricSem::Counter, Units::none(), 0u64, "", "")?;
+    assert!(matches!(mmv.resume(&mut [&mut d]), Err(Error::SchemaMismatch(_))));
+    assert!(matches!(c.inc(), Err(Error::NotMapped)));
+    fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use error::Error;
+
+/// Scale of the space dimension of a metric's units
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SpaceScale {
+    Byte = 0,
+    KByte = 1,
+    MByte = 2,
+    GByte = 3,
+    TByte = 4,
+    PByte = 5,
+    EByte = 6
+}
+
+/// Scale of the time dimension of a metric's units
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TimeScale {
+    Nanosec = 0,
+    Microsec = 1,
+    Millisec = 2,
+    Sec = 3,
+    Min = 4,
+    Hour = 5
+}
+
+/// Units of a metric's value, as dimensions of space, time and count
+/// along with the scale of each dimension
+///
+/// Each dimension is a power between -8 and 7, e.g. a space dimension of 1
+/// and time dimension of -1 is bytes per unit of time. The count scale is
+/// a power of ten.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Units {
+    dim_space: i8,
+    dim_time: i8,
+    dim_count: i8,
+    scale_space: SpaceScale,
+    scale_time: TimeScale,
+    scale_count: i8
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units::none()
+    }
+}
+
+fn check_dim(dim: i8) -> Result<(), Error> {
+    if !(-8..=7).contains(&dim) {
+        return Err(Error::DimensionOutOfRange(dim));
+    }
+    Ok(())
+}
+
+impl Units {
+    /// Dimensionless units
+    pub fn none() -> Self {
+        Units {
+            dim_space: 0,
+            dim_time: 0,
+            dim_count: 0,
+            scale_space: SpaceScale::Byte,
+            scale_time: TimeScale::Nanosec,
+            scale_count: 0
+        }
+    }
+
+    pub fn bytes(scale: SpaceScale) -> Self {
+        Units { dim_space: 1, scale_space: scale, ..Units::none() }
+    }
+
+    pub fn time(scale: TimeScale) -> Self {
+        Units { dim_time: 1, scale_time: scale, ..Units::none() }
+    }
+
+    pub fn count() -> Self {
+        Units { dim_count: 1, ..Units::none() }
+    }
+
+    pub fn bytes_per_sec(scale: SpaceScale) -> Self {
+        Units { dim_time: -1, scale_time: TimeScale::Sec, ..Units::bytes(scale) }
+    }
+
+    pub fn count_per_sec() -> Self {
+        Units { dim_time: -1, scale_time: TimeScale::Sec, ..Units::count() }
+    }
+
+    /// Sets the power and scale of the space dimension
+    pub fn with_space(mut self, dim: i8, scale: SpaceScale) -> Result<Self, Error> {
+        check_dim(dim)?;
+        self.dim_space = dim;
+        self.scale_space = scale;
+        Ok(self)
+    }
+
+    /// Sets the power and scale of the time dimension
+    pub fn with_time(mut self, dim: i8, scale: TimeScale) -> Result<Self, Error> {
+        check_dim(dim)?;
+        self.dim_time = dim;
+        self.scale_time = scale;
+        Ok(self)
+    }
+
+    /// Sets the power of the count dimension, and its scale as a power of
+    /// ten
+    pub fn with_count(mut self, dim: i8, scale: i8) -> Result<Self, Error> {
+        check_dim(dim)?;
+        check_dim(scale)?;
+        self.dim_count = dim;
+        self.scale_count = scale;
+        Ok(self)
+    }
+
+    /// Divides the units by a unit of time, e.g. turning bytes into bytes
+    /// per second
+    pub fn per_time(self, scale: TimeScale) -> Result<Self, Error> {
+        let dim = self.dim_time - 1;
+        self.with_time(dim, scale)
+    }
+
+    /// Unpacks units from PCP's 32-bit pmUnits bitfield, or returns
+    /// `None` if a scale is out of range
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        // sign-extends a 4-bit field
+        let nibble = |shift: u32| ((raw >> shift << 4) as u8 as i8) >> 4;
+        let scale_space = match (raw >> 16) & 0xf {
+            0 => SpaceScale::Byte,
+            1 => SpaceScale::KByte,
+            2 => SpaceScale::MByte,
+            3 => SpaceScale::GByte,
+            4 => SpaceScale::TByte,
+            5 => SpaceScale::PByte,
+            6 => SpaceScale::EByte,
+            _ => return None
+        };
+        let scale_time = match (raw >> 12) & 0xf {
+            0 => TimeScale::Nanosec,
+            1 => TimeScale::Microsec,
+            2 => TimeScale::Millisec,
+            3 => TimeScale::Sec,
+            4 => TimeScale::Min,
+            5 => TimeScale::Hour,
+            _ => return None
+        };
+        Some(Units {
+            dim_space: nibble(28),
+            dim_time: nibble(24),
+            dim_count: nibble(20),
+            scale_space: scale_space,
+            scale_time: scale_time,
+            scale_count: nibble(8)
+        })
+    }
+
+    /// Packs the units into PCP's 32-bit pmUnits bitfield
+    pub fn to_raw(&self) -> u32 {
+        let nibble = |x: i8| (x as u32) & 0xf;
+        nibble(self.dim_space) << 28 |
+        nibble(self.dim_time) << 24 |
+        nibble(self.dim_count) << 20 |
+        (self.scale_space as u32) << 16 |
+        (self.scale_time as u32) << 12 |
+        nibble(self.scale_count) << 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // values of PCP's pmUnits for the same units, as packed by libpcp
+    #[test]
+    fn to_raw() -> Result<(), Error> {
+        assert_eq!(Units::none().to_raw(), 0);
+        assert_eq!(Units::bytes(SpaceScale::KByte).to_raw(), 0x10010000);
+        assert_eq!(Units::time(TimeScale::Millisec).to_raw(), 0x01002000);
+        assert_eq!(Units::count().to_raw(), 0x00100000);
+        assert_eq!(Units::bytes_per_sec(SpaceScale::MByte).to_raw(), 0x1f023000);
+        assert_eq!(Units::count_per_sec().to_raw(), 0x0f103000);
+        assert_eq!(Units::none().with_count(1, 3)?.to_raw(), 0x00100300);
+        assert_eq!(Units::none().with_count(-2, -1)?.to_raw(), 0x00e00f00);
+        Ok(())
+    }
+
+    #[test]
+    fn from_raw() -> Result<(), Error> {
+        assert_eq!(Units::from_raw(0x10010000), Some(Units::bytes(SpaceScale::KByte)));
+        assert_eq!(Units::from_raw(0x1f023000), Some(Units::bytes_per_sec(SpaceScale::MByte)));
+        assert_eq!(Units::from_raw(0x00e00f00), Some(Units::none().with_count(-2, -1)?));
+        // space scale 7 and time scale 6 aren't defined
+        assert_eq!(Units::from_raw(0x10070000), None);
+        assert_eq!(Units::from_raw(0x01006000), None);
+        Ok(())
+    }
+}